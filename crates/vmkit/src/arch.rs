@@ -3,3 +3,107 @@
 //!
 //! Some of the code is generated using macroassembler so we get more or less portable code.
 
+use mmtk::util::Address;
+
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "x86_64")] {
+        /// rbx, rbp, rsi, rdi, r12-r15. `rsi` and `rdi` are only callee-saved on Windows but
+        /// spilling them on SysV is harmless.
+        pub const NUM_SAVED_REGISTERS: usize = 8;
+        /// Index of `rbp` in [`SavedRegisters`].
+        pub const FRAME_POINTER_INDEX: usize = 1;
+    } else if #[cfg(target_arch = "aarch64")] {
+        /// x19-x29.
+        pub const NUM_SAVED_REGISTERS: usize = 11;
+        /// Index of `x29` in [`SavedRegisters`].
+        pub const FRAME_POINTER_INDEX: usize = 10;
+    } else if #[cfg(target_arch = "riscv64")] {
+        /// s0-s11.
+        pub const NUM_SAVED_REGISTERS: usize = 12;
+        /// Index of `s0` in [`SavedRegisters`].
+        pub const FRAME_POINTER_INDEX: usize = 0;
+    } else {
+        pub const NUM_SAVED_REGISTERS: usize = 0;
+        pub const FRAME_POINTER_INDEX: usize = 0;
+    }
+}
+
+/// Callee-saved registers of a thread captured by [`save_registers`].
+pub type SavedRegisters = [usize; NUM_SAVED_REGISTERS];
+
+/// Spill all callee-saved registers into `registers`, returns stack pointer and instruction pointer of the caller.
+///
+/// Always inlined: returned state describes the frame of the caller, which must stay alive for as long as the state
+/// is used (e.g while the thread is blocked). Values the caller keeps in other registers are spilled to its frame
+/// before its next call, so once the caller blocks every value of its frame and frames above it is either in `registers`
+/// or on the stack above the returned stack pointer. This is what conservative stack scanning relies on.
+#[inline(always)]
+pub fn save_registers(registers: &mut SavedRegisters) -> (Address, Address) {
+    let sp: usize;
+    let ip: usize;
+    unsafe {
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "x86_64")] {
+                std::arch::asm!(
+                    "lea {ip}, [rip]",
+                    "mov [{buf}], rbx",
+                    "mov [{buf} + 8], rbp",
+                    "mov [{buf} + 16], rsi",
+                    "mov [{buf} + 24], rdi",
+                    "mov [{buf} + 32], r12",
+                    "mov [{buf} + 40], r13",
+                    "mov [{buf} + 48], r14",
+                    "mov [{buf} + 56], r15",
+                    "mov {sp}, rsp",
+                    buf = in(reg) registers.as_mut_ptr(),
+                    sp = out(reg) sp,
+                    ip = out(reg) ip,
+                    options(nostack, preserves_flags)
+                );
+            } else if #[cfg(target_arch = "aarch64")] {
+                std::arch::asm!(
+                    "adr {ip}, .",
+                    "stp x19, x20, [{buf}]",
+                    "stp x21, x22, [{buf}, #16]",
+                    "stp x23, x24, [{buf}, #32]",
+                    "stp x25, x26, [{buf}, #48]",
+                    "stp x27, x28, [{buf}, #64]",
+                    "str x29, [{buf}, #80]",
+                    "mov {sp}, sp",
+                    buf = in(reg) registers.as_mut_ptr(),
+                    sp = out(reg) sp,
+                    ip = out(reg) ip,
+                    options(nostack, preserves_flags)
+                );
+            } else if #[cfg(target_arch = "riscv64")] {
+                std::arch::asm!(
+                    "auipc {ip}, 0",
+                    "sd s0, 0({buf})",
+                    "sd s1, 8({buf})",
+                    "sd s2, 16({buf})",
+                    "sd s3, 24({buf})",
+                    "sd s4, 32({buf})",
+                    "sd s5, 40({buf})",
+                    "sd s6, 48({buf})",
+                    "sd s7, 56({buf})",
+                    "sd s8, 64({buf})",
+                    "sd s9, 72({buf})",
+                    "sd s10, 80({buf})",
+                    "sd s11, 88({buf})",
+                    "mv {sp}, sp",
+                    buf = in(reg) registers.as_mut_ptr(),
+                    sp = out(reg) sp,
+                    ip = out(reg) ip,
+                    options(nostack, preserves_flags)
+                );
+            } else {
+                let _ = registers;
+                let marker = 0usize;
+                sp = &marker as *const usize as usize;
+                ip = 0;
+            }
+        }
+    }
+
+    unsafe { (Address::from_usize(sp), Address::from_usize(ip)) }
+}
@@ -1 +1,45 @@
+//! # Compiler support
+//!
+//! Helpers for JIT compilers built on top of [`macroassembler`]: inline fast paths for VMKit runtime
+//! operations which fall back to `extern "C"` entrypoints in [`mm`](crate::mm) on the slow path.
 
+use macroassembler::assembler::TargetMacroAssembler;
+
+pub mod allocation;
+pub mod barriers;
+
+/// Move `(src, dst)` register pairs in parallel, e.g to place values into argument registers before a call.
+/// Cycles are broken with macroassembler scratch register.
+pub(crate) fn move_registers(masm: &mut TargetMacroAssembler, moves: &[(u8, u8)]) {
+    let scratch = TargetMacroAssembler::SCRATCH_REGISTER;
+    let mut pending = moves
+        .iter()
+        .copied()
+        .filter(|(src, dst)| src != dst)
+        .collect::<Vec<_>>();
+
+    while !pending.is_empty() {
+        // emit move whose destination is not read by any other pending move
+        let ready = pending
+            .iter()
+            .position(|&(_, dst)| pending.iter().all(|&(src, _)| src != dst));
+
+        match ready {
+            Some(ix) => {
+                let (src, dst) = pending.remove(ix);
+                masm.mov(src, dst);
+            }
+
+            None => {
+                // every destination is still read: cycle, park first source in scratch
+                let (src, _) = pending[0];
+                masm.mov(src, scratch);
+                for (pending_src, _) in pending.iter_mut() {
+                    if *pending_src == src {
+                        *pending_src = scratch;
+                    }
+                }
+            }
+        }
+    }
+}
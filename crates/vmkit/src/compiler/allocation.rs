@@ -0,0 +1,128 @@
+//! # Inline allocation
+//!
+//! Emits TLAB bump-pointer allocation directly into JIT code. Fast path loads cursor and limit from
+//! [`TLSData`] at [`TLAB_CURSOR_OFFSET`](TLSData::TLAB_CURSOR_OFFSET) and [`TLAB_LIMIT_OFFSET`](TLSData::TLAB_LIMIT_OFFSET),
+//! bumps the cursor and stores object header (and mark word, if enabled). When TLAB is exhausted [`vmkit_allocate`] is called.
+//!
+//! Slow path follows C calling convention: all caller-saved registers are clobbered, JIT must spill live values
+//! (and record stack map for the call if it holds references).
+
+use macroassembler::{
+    assembler::{
+        abstract_macro_assembler::{Address, Call},
+        RelationalCondition, TargetMacroAssembler,
+    },
+    jit::gpr_info::{ARGUMENT_GPR0, ARGUMENT_GPR1, ARGUMENT_GPR2, RETURN_VALUE_GPR},
+};
+
+use crate::{
+    mm::{finalization::Finalization, sampling, stress, tlab::TLAB, vmkit_allocate},
+    objectmodel::{
+        constants::MARK_WORD_SIZE, header::HeapObjectHeader, vtable::VTablePointer, ObjectModel,
+    },
+    runtime::threads::TLSData,
+    Runtime,
+};
+
+/// Registers used by [`emit_allocation`].
+#[derive(Clone, Copy, Debug)]
+pub struct AllocationRegisters {
+    /// Holds `VMMutatorThread` of current thread, passed to the slow path.
+    pub thread: u8,
+    /// Holds pointer to [`TLSData`] of current thread.
+    pub tls: u8,
+    /// Receives allocated object reference.
+    pub result: u8,
+    /// Clobbered by the fast path. Must differ from all other registers.
+    pub scratch: u8,
+}
+
+/// Emit allocation of an object of `size` bytes (header included) with `vtable`.
+///
+/// Returns the slow path call so that JIT can record stack map for it with [`StackMaps::record`](crate::mm::stack_map::StackMaps::record).
+///
+/// Fast path is skipped entirely and only slow path is emitted when:
+/// - Runtime requires VO bits: TLAB bypasses MMTk allocators and VO bit must be set by [`vmkit_allocate`].
+/// - `vtable` requires finalization.
+/// - `size` exceeds large object space threshold.
+/// - GC stress mode or allocation sampling is enabled, so every allocation reaches their hooks in [`vmkit_allocate`].
+pub fn emit_allocation<R: Runtime>(
+    masm: &mut TargetMacroAssembler,
+    regs: AllocationRegisters,
+    size: usize,
+    vtable: VTablePointer,
+) -> Call {
+    let los_threshold = R::vmkit()
+        .mmtk
+        .get_plan()
+        .constraints()
+        .max_non_los_default_alloc_bytes;
+
+    let total_size = size + ObjectModel::<R>::MARK_WORD_SIZE;
+    let inline = !R::VO_BIT
+        && !Finalization::<R>::needs_finalization(vtable)
+        && total_size < los_threshold
+        && total_size <= i32::MAX as usize
+        && !stress::is_enabled()
+        && sampling::sampling_interval() == 0;
+
+    if !inline {
+        return emit_slow_path::<R>(masm, regs, size, vtable);
+    }
+
+    let cursor = Address::new(regs.tls, TLSData::<R>::TLAB_CURSOR_OFFSET as i32);
+    let limit = Address::new(regs.tls, TLSData::<R>::TLAB_LIMIT_OFFSET as i32);
+    let align_mask = (TLAB::<R>::ALIGNMENT - 1) as i32;
+
+    // result = align_up(cursor); scratch = result + size
+    masm.load64(cursor, regs.result);
+    masm.add64(align_mask, regs.result);
+    masm.and64(!align_mask, regs.result);
+    masm.mov(regs.result, regs.scratch);
+    masm.add64(total_size as i32, regs.scratch);
+
+    // same check as `TLAB::allocate`
+    let slow = masm.branch64(RelationalCondition::AboveOrEqual, regs.scratch, limit);
+    masm.store64(regs.scratch, cursor);
+
+    // mark word of a new object is zero: unlocked, age 0
+    if R::MARK_WORD {
+        masm.mov(0i64, regs.scratch);
+        masm.store64(regs.scratch, Address::new(regs.result, 0));
+        masm.add64(MARK_WORD_SIZE as i32, regs.result);
+    }
+
+    // header is a single word: vtable pointer with hash state and GC bits cleared
+    let header = HeapObjectHeader::<R>::new(vtable);
+    let header_word = unsafe { mmtk::util::Address::from_ref(&header).load::<usize>() };
+    masm.mov(header_word as i64, regs.scratch);
+    masm.store64(regs.scratch, Address::new(regs.result, 0));
+    masm.add64(size_of::<HeapObjectHeader<R>>() as i32, regs.result);
+
+    let done = masm.jump();
+    slow.link(masm);
+    let call = emit_slow_path::<R>(masm, regs, size, vtable);
+    done.link(masm);
+
+    call
+}
+
+fn emit_slow_path<R: Runtime>(
+    masm: &mut TargetMacroAssembler,
+    regs: AllocationRegisters,
+    size: usize,
+    vtable: VTablePointer,
+) -> Call {
+    masm.mov(regs.thread, ARGUMENT_GPR0);
+    masm.mov(size as i64, ARGUMENT_GPR1);
+    masm.mov(vtable.0.to_address().as_usize() as i64, ARGUMENT_GPR2);
+    masm.mov(
+        vmkit_allocate::<R> as usize as i64,
+        TargetMacroAssembler::SCRATCH_REGISTER,
+    );
+    let call = masm
+        .call_op(Some(TargetMacroAssembler::SCRATCH_REGISTER))
+        .expect("register call always produces Call");
+    masm.mov(RETURN_VALUE_GPR, regs.result);
+    call
+}
@@ -0,0 +1,103 @@
+//! # Inline write barriers
+//!
+//! Emits post-write barrier into JIT code. What is emitted depends on [`barrier_kind`]:
+//!
+//! - [`BarrierKind::NoBarrier`]: nothing.
+//! - [`BarrierKind::ObjectRemembering`]: inline check of the unlogged bit of the source object (same as [`is_unlogged`](crate::mm::barriers::is_unlogged)),
+//!   [`vmkit_write_barrier_post_slow`] is called only when the bit is set.
+//! - [`BarrierKind::Generic`]: unconditional call to [`vmkit_write_barrier_post_slow`].
+//!
+//! Barrier kind is fixed once VMKit is built, code must be emitted after that.
+
+use macroassembler::{
+    assembler::{
+        abstract_macro_assembler::{Address, Call},
+        ResultCondition, TargetMacroAssembler,
+    },
+    jit::gpr_info::{ARGUMENT_GPR0, ARGUMENT_GPR1, ARGUMENT_GPR2},
+};
+
+use crate::{
+    compiler::move_registers,
+    mm::{
+        barriers::{barrier_kind, BarrierKind, UNLOGGED_BIT_TABLE},
+        vmkit_write_barrier_post_slow,
+    },
+    Runtime, SlotOf,
+};
+
+/// Registers used by [`emit_post_write_barrier`].
+#[derive(Clone, Copy, Debug)]
+pub struct WriteBarrierRegisters {
+    /// Object that was written into.
+    pub object: u8,
+    /// Address of the slot that was written.
+    pub slot: u8,
+    /// Value that was stored, zero for null.
+    pub target: u8,
+    /// Clobbered by the barrier. Must differ from all other registers.
+    pub scratch0: u8,
+    /// Clobbered by the barrier. Must differ from all other registers.
+    pub scratch1: u8,
+}
+
+/// Emit post-write barrier for a store that was already performed.
+///
+/// Slow path follows C calling convention: all caller-saved registers are clobbered, JIT must spill live values.
+/// Returns the slow path call so that JIT can record stack map for it, or `None` when plan requires no barrier.
+pub fn emit_post_write_barrier<R: Runtime>(
+    masm: &mut TargetMacroAssembler,
+    regs: WriteBarrierRegisters,
+) -> Option<Call> {
+    assert_eq!(
+        size_of::<SlotOf<R>>(),
+        size_of::<usize>(),
+        "inline barriers pass slot as a raw pointer"
+    );
+
+    match barrier_kind() {
+        BarrierKind::NoBarrier => None,
+        BarrierKind::ObjectRemembering => {
+            // scratch0 = UNLOGGED_BIT_TABLE[object >> 6]
+            masm.mov(regs.object, regs.scratch0);
+            masm.urshift64(6i32, regs.scratch0);
+            masm.mov(UNLOGGED_BIT_TABLE.as_usize() as i64, regs.scratch1);
+            masm.add64(regs.scratch1, regs.scratch0);
+            masm.load8(Address::new(regs.scratch0, 0), regs.scratch0);
+
+            // scratch1 = (object >> 3) & 7
+            masm.mov(regs.object, regs.scratch1);
+            masm.urshift64(3i32, regs.scratch1);
+            masm.and32(7i32, regs.scratch1);
+
+            masm.urshift32(regs.scratch1, regs.scratch0);
+            let logged = masm.branch_test32(ResultCondition::Zero, regs.scratch0, 1i32);
+            let call = emit_slow_path::<R>(masm, regs);
+            logged.link(masm);
+
+            Some(call)
+        }
+        BarrierKind::Generic => Some(emit_slow_path::<R>(masm, regs)),
+    }
+}
+
+fn emit_slow_path<R: Runtime>(
+    masm: &mut TargetMacroAssembler,
+    regs: WriteBarrierRegisters,
+) -> Call {
+    move_registers(
+        masm,
+        &[
+            (regs.object, ARGUMENT_GPR0),
+            (regs.slot, ARGUMENT_GPR1),
+            (regs.target, ARGUMENT_GPR2),
+        ],
+    );
+
+    masm.mov(
+        vmkit_write_barrier_post_slow::<R> as usize as i64,
+        TargetMacroAssembler::SCRATCH_REGISTER,
+    );
+    masm.call_op(Some(TargetMacroAssembler::SCRATCH_REGISTER))
+        .expect("register call always produces Call")
+}
@@ -1,28 +1,64 @@
 use crate::{
-    mm::slot::SlotExt,
-    objectmodel::{header::HeapObjectHeader, vtable::VTablePointer},
-    runtime::threads::*,
-    MMTKVMKit, Runtime, SlotOf, ThreadOf,
+    mm::{
+        barriers::{barrier_kind, BarrierKind},
+        finalization::Finalization,
+        memory_slice::VMMemorySlice,
+        slot::SlotExt,
+        tlab::TLAB,
+    },
+    objectmodel::{
+        constants::MARK_WORD_SIZE,
+        header::HeapObjectHeader,
+        mark_word::MarkWord,
+        vtable::{VTable, VTablePointer},
+        ObjectModel,
+    },
+    runtime::{threads::*, DisableGCScope},
+    MMTKVMKit, Runtime, SlotOf, ThreadOf, VTableOf,
 };
-use atomic::Atomic;
 use mmtk::{
-    util::{
-        metadata::side_metadata::GLOBAL_SIDE_METADATA_VM_BASE_ADDRESS, ObjectReference,
-        VMMutatorThread,
-    },
+    util::{Address, ObjectReference, VMMutatorThread},
+    vm::slot::MemorySlice,
     MutatorContext,
 };
 
 pub mod active_plan;
+pub mod barriers;
 pub mod collection;
+#[cfg(feature = "vo-bit")]
+pub mod conservative_roots;
+pub mod finalization;
+pub mod heap_snapshot;
+pub mod heap_walk;
+pub mod memory_slice;
+pub mod pinning;
+#[cfg(feature = "compressed-oops")]
 pub mod ptr_compr;
+pub mod references;
 pub mod roots;
+pub mod sampling;
 pub mod scanning;
 pub mod shadow_stack;
 pub mod slot;
+pub mod stack_map;
+pub mod stats;
+pub mod stress;
 pub mod tlab;
+pub mod verifier;
 
-pub(crate) static GENERATIONAL_PLAN: Atomic<bool> = Atomic::new(false);
+/// Write mark word (if enabled) and header of an object allocated at `start`, returns reference to the object.
+#[inline(always)]
+unsafe fn initialize_header<R: Runtime>(start: Address, vtable: VTablePointer) -> ObjectReference {
+    let mut result = start;
+    if R::MARK_WORD {
+        result.store(MarkWord::new());
+        result += MARK_WORD_SIZE;
+    }
+
+    result.store(HeapObjectHeader::<R>::new(vtable));
+    result += size_of::<HeapObjectHeader<R>>();
+    ObjectReference::from_raw_address_unchecked(result)
+}
 
 #[inline]
 pub extern "C" fn vmkit_allocate<R: Runtime>(
@@ -30,34 +66,138 @@ pub extern "C" fn vmkit_allocate<R: Runtime>(
     size: usize,
     vtable: VTablePointer,
 ) -> ObjectReference {
+    vmkit_allocate_with::<R>(thread, size, vtable, |_| {})
+}
+
+/// Allocate an object of `size` bytes (header included) and run `init` on it before the object
+/// becomes visible to GC: before VO bit is set and before it is registered for finalization.
+///
+/// Body of the object is uninitialized when `init` runs, `init` must fill in everything that `trace` and
+/// `compute_size` read. `init` must not allocate or reach a yieldpoint, object is not rooted yet.
+#[inline]
+pub fn vmkit_allocate_with<R: Runtime>(
+    thread: VMMutatorThread,
+    size: usize,
+    vtable: VTablePointer,
+    init: impl FnOnce(ObjectReference),
+) -> ObjectReference {
+    stress::on_allocation::<R>(thread);
+    let size = size + ObjectModel::<R>::MARK_WORD_SIZE;
     let tls = ThreadOf::<R>::tls(thread.0);
 
     unsafe {
         let tlab = tls.tlab_mut_unchecked();
         let mmtk_mutator = tls.mutator_mut_unchecked();
 
-        let mut result = tlab.allocate(mmtk_mutator, size, align_of::<usize>() * 2);
+        let (result, semantics) = tlab.allocate(mmtk_mutator, size, TLAB::<R>::ALIGNMENT, vtable);
         assert!(!result.is_zero(), "oom");
-        result.store(HeapObjectHeader::<R>::new(vtable));
-        result += size_of::<HeapObjectHeader<R>>();
-        let refer = ObjectReference::from_raw_address_unchecked(result);
+        let refer = initialize_header::<R>(result, vtable);
+
+        init(refer);
+
+        // TLAB bypasses MMTk allocators: VO bit has to be set explicitly, and large objects
+        // must be added to LOS metadata.
+        if R::VO_BIT || semantics != mmtk::AllocationSemantics::Default {
+            mmtk::memory_manager::post_alloc(mmtk_mutator, refer, size, semantics);
+        }
+
+        if Finalization::<R>::needs_finalization(vtable) {
+            R::vmkit().finalization.register(refer);
+        }
 
         refer
     }
 }
 
+/// Size of the object body, i.e `size` without the header. Returns `None` if `size` can't hold the header.
+#[inline(always)]
+fn body_size<R: Runtime>(size: usize) -> Option<usize> {
+    size.checked_sub(size_of::<HeapObjectHeader<R>>())
+}
+
+/// Same as [`vmkit_allocate`] but body of the object is zeroed, i.e all reference fields are null.
+///
+/// Returns `None` (null) if `size` is smaller than object header.
+#[inline]
+pub extern "C" fn vmkit_allocate_zeroed<R: Runtime>(
+    thread: VMMutatorThread,
+    size: usize,
+    vtable: VTablePointer,
+) -> Option<ObjectReference> {
+    let body = body_size::<R>(size)?;
+    Some(vmkit_allocate_with::<R>(
+        thread,
+        size,
+        vtable,
+        |object| unsafe {
+            std::ptr::write_bytes(object.to_raw_address().to_mut_ptr::<u8>(), 0, body);
+        },
+    ))
+}
+
+/// Size of an array-like object: `header_size` bytes of fixed part (object header included) followed by
+/// `length` elements of `element_size` bytes, rounded up to word size. Returns `None` on overflow.
+#[inline]
+pub const fn array_size(header_size: usize, element_size: usize, length: usize) -> Option<usize> {
+    let Some(elements) = element_size.checked_mul(length) else {
+        return None;
+    };
+    let Some(size) = header_size.checked_add(elements) else {
+        return None;
+    };
+    let Some(size) = size.checked_add(size_of::<usize>() - 1) else {
+        return None;
+    };
+
+    if size > isize::MAX as usize {
+        return None;
+    }
+
+    Some(size & !(size_of::<usize>() - 1))
+}
+
+/// Allocate a zeroed array-like object, see [`array_size`]. Returns `None` if size overflows or can't hold the header.
+#[inline]
+pub extern "C" fn vmkit_allocate_array<R: Runtime>(
+    thread: VMMutatorThread,
+    header_size: usize,
+    element_size: usize,
+    length: usize,
+    vtable: VTablePointer,
+) -> Option<ObjectReference> {
+    let size = array_size(header_size, element_size, length)?;
+    vmkit_allocate_zeroed::<R>(thread, size, vtable)
+}
+
+/// Allocate an array-like object and run `init` on it before it becomes visible to GC, see [`vmkit_allocate_with`].
+/// Returns `None` if size overflows.
+#[inline]
+pub fn vmkit_allocate_array_with<R: Runtime>(
+    thread: VMMutatorThread,
+    header_size: usize,
+    element_size: usize,
+    length: usize,
+    vtable: VTablePointer,
+    init: impl FnOnce(ObjectReference),
+) -> Option<ObjectReference> {
+    let size = array_size(header_size, element_size, length)?;
+    Some(vmkit_allocate_with::<R>(thread, size, vtable, init))
+}
+
 #[inline]
 pub extern "C" fn vmkit_allocate_immortal<R: Runtime>(
     thread: VMMutatorThread,
     size: usize,
     vtable: VTablePointer,
 ) -> ObjectReference {
+    stress::on_allocation::<R>(thread);
+    let size = size + ObjectModel::<R>::MARK_WORD_SIZE;
     let tls = ThreadOf::<R>::tls(thread.0);
     unsafe {
         let tlab = tls.tlab_mut_unchecked();
         let mmtk_mutator = tls.mutator_mut_unchecked();
         tlab.flush_cursors(mmtk_mutator);
-        let mut result = mmtk::memory_manager::alloc(
+        let result = mmtk::memory_manager::alloc(
             mmtk_mutator,
             size,
             align_of::<usize>() * 2,
@@ -66,10 +206,17 @@ pub extern "C" fn vmkit_allocate_immortal<R: Runtime>(
         );
         tlab.bump_cursors(mmtk_mutator);
 
-        result.store(HeapObjectHeader::<R>::new(vtable));
-        result += size_of::<HeapObjectHeader<R>>();
+        let refer = initialize_header::<R>(result, vtable);
+        mmtk::memory_manager::post_alloc(
+            mmtk_mutator,
+            refer,
+            size,
+            mmtk::AllocationSemantics::Immortal,
+        );
 
-        let refer = ObjectReference::from_raw_address_unchecked(result);
+        if Finalization::<R>::needs_finalization(vtable) {
+            R::vmkit().finalization.register(refer);
+        }
 
         refer
     }
@@ -81,12 +228,14 @@ pub extern "C" fn vmkit_allocate_nonmoving<R: Runtime>(
     size: usize,
     vtable: VTablePointer,
 ) -> ObjectReference {
+    stress::on_allocation::<R>(thread);
+    let size = size + ObjectModel::<R>::MARK_WORD_SIZE;
     let tls = ThreadOf::<R>::tls(thread.0);
     unsafe {
         let tlab = tls.tlab_mut_unchecked();
         let mmtk_mutator = tls.mutator_mut_unchecked();
         tlab.flush_cursors(mmtk_mutator);
-        let mut result = mmtk::memory_manager::alloc(
+        let result = mmtk::memory_manager::alloc(
             mmtk_mutator,
             size,
             align_of::<usize>() * 2,
@@ -94,10 +243,17 @@ pub extern "C" fn vmkit_allocate_nonmoving<R: Runtime>(
             mmtk::AllocationSemantics::NonMoving,
         );
         tlab.bump_cursors(mmtk_mutator);
-        result.store(HeapObjectHeader::<R>::new(vtable));
-        result += size_of::<HeapObjectHeader<R>>();
+        let refer = initialize_header::<R>(result, vtable);
+        mmtk::memory_manager::post_alloc(
+            mmtk_mutator,
+            refer,
+            size,
+            mmtk::AllocationSemantics::NonMoving,
+        );
 
-        let refer = ObjectReference::from_raw_address_unchecked(result);
+        if Finalization::<R>::needs_finalization(vtable) {
+            R::vmkit().finalization.register(refer);
+        }
 
         refer
     }
@@ -109,12 +265,14 @@ pub extern "C" fn vmkit_allocate_los<R: Runtime>(
     size: usize,
     vtable: VTablePointer,
 ) -> ObjectReference {
+    stress::on_allocation::<R>(thread);
+    let size = size + ObjectModel::<R>::MARK_WORD_SIZE;
     let tls = ThreadOf::<R>::tls(thread.0);
     unsafe {
         let tlab = tls.tlab_mut_unchecked();
         let mmtk_mutator = tls.mutator_mut_unchecked();
         tlab.flush_cursors(mmtk_mutator);
-        let mut result = mmtk::memory_manager::alloc(
+        let result = mmtk::memory_manager::alloc(
             mmtk_mutator,
             size,
             align_of::<usize>() * 2,
@@ -122,10 +280,14 @@ pub extern "C" fn vmkit_allocate_los<R: Runtime>(
             mmtk::AllocationSemantics::Los,
         );
         tlab.bump_cursors(mmtk_mutator);
-        result.store(HeapObjectHeader::<R>::new(vtable));
-        result += size_of::<HeapObjectHeader<R>>();
+        let refer = initialize_header::<R>(result, vtable);
+        mmtk::memory_manager::post_alloc(mmtk_mutator, refer, size, mmtk::AllocationSemantics::Los);
 
-        ObjectReference::from_raw_address_unchecked(result)
+        if Finalization::<R>::needs_finalization(vtable) {
+            R::vmkit().finalization.register(refer);
+        }
+
+        refer
     }
 }
 
@@ -137,7 +299,7 @@ pub extern "C" fn vmkit_write_barrier_post<R: Runtime>(
 ) {
     let tls = ThreadOf::<R>::tls(thread.0);
 
-    if tls.is_generational {
+    if barrier_kind().needs_post_barrier() {
         let slot = SlotOf::<R>::from_pointer(slot);
         unsafe {
             mmtk::memory_manager::object_reference_write_post(
@@ -150,25 +312,209 @@ pub extern "C" fn vmkit_write_barrier_post<R: Runtime>(
     }
 }
 
+/// Pre-write barrier, must be invoked before `slot` of `src` is overwritten with `target`.
+/// Required by snapshot-at-the-beginning plans, no-op otherwise.
+pub extern "C" fn vmkit_write_barrier_pre<R: Runtime>(
+    thread: VMMutatorThread,
+    src: ObjectReference,
+    slot: *mut ObjectReference,
+    target: Option<ObjectReference>,
+) {
+    if barrier_kind().needs_pre_barrier() {
+        let tls = ThreadOf::<R>::tls(thread.0);
+        let slot = SlotOf::<R>::from_pointer(slot);
+        unsafe {
+            mmtk::memory_manager::object_reference_write_pre(
+                tls.mutator_mut_unchecked(),
+                src,
+                slot,
+                target,
+            )
+        }
+    }
+}
+
+/// Same as [`vmkit_write_barrier_pre`] except fetches current thread on its own.
+pub extern "C" fn vmkit_reference_write_pre<R: Runtime>(
+    src: ObjectReference,
+    slot: SlotOf<R>,
+    target: Option<ObjectReference>,
+) {
+    if barrier_kind().needs_pre_barrier() {
+        unsafe {
+            let tls = vmkit_get_tls::<R>();
+
+            tls.mutator_mut_unchecked()
+                .barrier()
+                .object_reference_write_pre(src, slot, target);
+        }
+    }
+}
+
 /// Same as [`vmkit_write_barrier_post`] except fetches current thread on its own.
 pub extern "C" fn vmkit_reference_write_post<R: Runtime>(
     src: ObjectReference,
     slot: SlotOf<R>,
     target: Option<ObjectReference>,
 ) {
-    if GENERATIONAL_PLAN.load(std::sync::atomic::Ordering::Relaxed) {
-        unsafe {
-            let addr = src.to_raw_address().as_usize();
-            let meta_addr = GLOBAL_SIDE_METADATA_VM_BASE_ADDRESS + (addr >> 6);
-            let shift = ((addr >> 3) & 0b111) as isize;
-            let byte_val = meta_addr.load::<u8>();
-            if (byte_val >> shift) & 1 == 1 {
+    match barrier_kind() {
+        BarrierKind::NoBarrier => (),
+        BarrierKind::ObjectRemembering => {
+            if barriers::is_unlogged(src) {
                 vmkit_write_barrier_post_slow::<R>(src, slot, target);
             }
         }
+        BarrierKind::Generic => vmkit_write_barrier_post_slow::<R>(src, slot, target),
     }
 }
 
+/// Pre-barrier for bulk copy of references from `src` to `dst`. Must be invoked before the copy.
+pub extern "C" fn vmkit_memory_region_copy_pre<R: Runtime>(
+    thread: VMMutatorThread,
+    src: &VMMemorySlice<R>,
+    dst: &VMMemorySlice<R>,
+) {
+    if barrier_kind() != BarrierKind::NoBarrier {
+        let tls = ThreadOf::<R>::tls(thread.0);
+        unsafe {
+            mmtk::memory_manager::memory_region_copy_pre(
+                tls.mutator_mut_unchecked(),
+                src.clone(),
+                dst.clone(),
+            );
+        }
+    }
+}
+
+/// Post-barrier for bulk copy of references from `src` to `dst`. Must be invoked after the copy.
+/// A single call covers the whole range instead of one barrier per element.
+pub extern "C" fn vmkit_memory_region_copy_post<R: Runtime>(
+    thread: VMMutatorThread,
+    src: &VMMemorySlice<R>,
+    dst: &VMMemorySlice<R>,
+) {
+    if barrier_kind() != BarrierKind::NoBarrier {
+        let tls = ThreadOf::<R>::tls(thread.0);
+        unsafe {
+            mmtk::memory_manager::memory_region_copy_post(
+                tls.mutator_mut_unchecked(),
+                src.clone(),
+                dst.clone(),
+            );
+        }
+    }
+}
+
+/// Copy `count` references from `src_start` (inside of `src` object) to `dst_start` (inside of `dst` object).
+///
+/// Ranges are allowed to overlap. Barriers are applied once for the whole range: a single batched
+/// barrier for generational plans instead of one barrier per element.
+///
+/// Only full-width reference slots are supported, panics if compressed heap layout is enabled.
+pub extern "C" fn vmkit_array_copy<R: Runtime>(
+    thread: VMMutatorThread,
+    src: ObjectReference,
+    src_start: *mut ObjectReference,
+    dst: ObjectReference,
+    dst_start: *mut ObjectReference,
+    count: usize,
+) {
+    if count == 0 {
+        return;
+    }
+
+    let src = VMMemorySlice::<R>::new(Some(src), Address::from_mut_ptr(src_start), count);
+    let dst = VMMemorySlice::<R>::new(Some(dst), Address::from_mut_ptr(dst_start), count);
+
+    vmkit_memory_region_copy_pre::<R>(thread, &src, &dst);
+    <VMMemorySlice<R> as MemorySlice>::copy(&src, &dst);
+    vmkit_memory_region_copy_post::<R>(thread, &src, &dst);
+}
+
+/// Store `value` into `count` slots starting at `start` inside of `dst` object.
+///
+/// Only full-width reference slots are supported, panics if compressed heap layout is enabled.
+pub extern "C" fn vmkit_array_fill<R: Runtime>(
+    thread: VMMutatorThread,
+    dst: ObjectReference,
+    start: *mut ObjectReference,
+    count: usize,
+    value: Option<ObjectReference>,
+) {
+    #[cfg(feature = "compressed-oops")]
+    assert!(
+        !ptr_compr::is_enabled(),
+        "vmkit_array_fill only supports full-width reference slots"
+    );
+    if count == 0 {
+        return;
+    }
+
+    let kind = barrier_kind();
+    let raw = value.map_or(Address::ZERO, |value| value.to_raw_address());
+
+    for ix in 0..count {
+        let slot = unsafe { start.add(ix) };
+
+        if kind.needs_pre_barrier() {
+            vmkit_write_barrier_pre::<R>(thread, dst, slot, value);
+        }
+
+        unsafe {
+            Address::from_mut_ptr(slot).store(raw);
+        }
+
+        if kind == BarrierKind::Generic {
+            vmkit_write_barrier_post::<R>(thread, dst, slot, value);
+        }
+    }
+
+    // Object-remembering barrier logs the whole object: one post-barrier covers all the stores.
+    if kind == BarrierKind::ObjectRemembering {
+        vmkit_write_barrier_post::<R>(thread, dst, start, value);
+    }
+}
+
+/// Allocate a shallow copy of `src`. Clone has the same vtable and contents but a fresh header (e.g it is not hashed).
+pub extern "C" fn vmkit_array_clone<R: Runtime>(
+    thread: VMMutatorThread,
+    src: ObjectReference,
+) -> ObjectReference {
+    let header = <&HeapObjectHeader<R>>::from(src);
+    let vtable = header.vtable();
+    let vt = VTableOf::<R>::from_pointer(vtable).gc();
+    let size = match vt.size() {
+        0 => vt.compute_size.expect("Must be available")(src).get(),
+        size => size,
+    };
+    // `src` is a live object: its size always covers the header.
+    let body = size - size_of::<HeapObjectHeader<R>>();
+
+    // allocation can trigger GC which moves `src`: keep it rooted in the shadow stack of `thread`
+    let mut src = src;
+    let clone = {
+        let frame = ThreadOf::<R>::tls(thread.0)
+            .shadow_stack()
+            .enter_roots_frame(1);
+        // `src` is declared outside of the frame and outlives it
+        unsafe { frame.save_root_unchecked(0, &mut src) };
+        let clone = vmkit_allocate::<R>(thread, size, vtable);
+        drop(frame);
+        clone
+    };
+
+    // Clone is a fresh object: no barriers are required for its initializing stores.
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            src.to_raw_address().to_ptr::<u8>(),
+            clone.to_raw_address().to_mut_ptr::<u8>(),
+            body,
+        );
+    }
+
+    clone
+}
+
 /// A slow-path for write-barrier.
 #[cold]
 #[inline(never)]
@@ -208,9 +554,7 @@ pub extern "C" fn vmkit_object_hash<R: Runtime>(object: ObjectReference) -> u64
     }
 }
 
+/// Request GC from the current mutator thread. Deferred while GC is disabled by [`DisableGCScope`](crate::runtime::DisableGCScope).
 pub extern "C" fn vmkit_request_gc<R: Runtime>() {
-    mmtk::memory_manager::handle_user_collection_request(
-        &R::vmkit().mmtk,
-        VMMutatorThread(vmkit_current_thread()),
-    );
+    DisableGCScope::<R>::request_gc(VMMutatorThread(vmkit_current_thread()));
 }
@@ -0,0 +1,84 @@
+//! # Write barriers
+//!
+//! Write barrier abstraction. The kind of barrier is selected once per process from the plan and all
+//! barrier entrypoints in [`mm`](crate::mm) dispatch on it:
+//!
+//! - [`BarrierKind::NoBarrier`]: non-generational plans, barriers are no-ops.
+//! - [`BarrierKind::ObjectRemembering`]: generational plans (GenCopy, GenImmix, StickyImmix). Post-barrier with an
+//!   inline check of the unlogged bit, slow-path is taken only for the first store into a mature object after GC.
+//! - [`BarrierKind::Generic`]: any other plan, e.g snapshot-at-the-beginning concurrent plans. Both pre- and
+//!   post-barriers are forwarded to MMTk mutator barrier, which knows what to do.
+//!
+//! JIT compilers should query [`barrier_kind`] to decide which fast path to emit.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use mmtk::util::{
+    metadata::side_metadata::GLOBAL_SIDE_METADATA_VM_BASE_ADDRESS, options::PlanSelector,
+    ObjectReference,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum BarrierKind {
+    NoBarrier = 0,
+    ObjectRemembering = 1,
+    Generic = 2,
+}
+
+impl BarrierKind {
+    pub fn from_plan(plan: PlanSelector) -> Self {
+        match plan {
+            PlanSelector::NoGC
+            | PlanSelector::SemiSpace
+            | PlanSelector::MarkSweep
+            | PlanSelector::PageProtect
+            | PlanSelector::Immix
+            | PlanSelector::MarkCompact => Self::NoBarrier,
+            PlanSelector::GenCopy | PlanSelector::GenImmix | PlanSelector::StickyImmix => {
+                Self::ObjectRemembering
+            }
+            #[allow(unreachable_patterns)]
+            _ => Self::Generic,
+        }
+    }
+
+    /// Does barrier require pre-write barrier invocation?
+    pub fn needs_pre_barrier(self) -> bool {
+        self == Self::Generic
+    }
+
+    pub fn needs_post_barrier(self) -> bool {
+        self != Self::NoBarrier
+    }
+}
+
+static BARRIER: AtomicU8 = AtomicU8::new(BarrierKind::NoBarrier as u8);
+
+pub(crate) fn set_barrier_kind(kind: BarrierKind) {
+    BARRIER.store(kind as u8, Ordering::Relaxed);
+}
+
+#[inline(always)]
+pub fn barrier_kind() -> BarrierKind {
+    match BARRIER.load(Ordering::Relaxed) {
+        0 => BarrierKind::NoBarrier,
+        1 => BarrierKind::ObjectRemembering,
+        _ => BarrierKind::Generic,
+    }
+}
+
+/// Base address of the global unlogged-bit table used by [`BarrierKind::ObjectRemembering`].
+pub const UNLOGGED_BIT_TABLE: mmtk::util::Address = GLOBAL_SIDE_METADATA_VM_BASE_ADDRESS;
+
+/// Is unlogged bit of `object` set i.e should object-remembering barrier take the slow path?
+#[inline(always)]
+pub fn is_unlogged(object: ObjectReference) -> bool {
+    unsafe {
+        let addr = object.to_raw_address().as_usize();
+        let meta_addr = UNLOGGED_BIT_TABLE + (addr >> 6);
+        let shift = ((addr >> 3) & 0b111) as isize;
+        let byte_val = meta_addr.load::<u8>();
+        (byte_val >> shift) & 1 == 1
+    }
+}
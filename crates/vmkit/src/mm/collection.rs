@@ -6,8 +6,12 @@ use mmtk::{
 };
 
 use crate::{
-    mm::active_plan::VMActivePlan,
-    runtime::threads::{self, GCBlockAdapter, Thread},
+    mm::{active_plan::VMActivePlan, stats::GcPhase, verifier},
+    runtime::{
+        options::mmtkflags_verify_heap,
+        threads::{self, GCBlockAdapter, Thread},
+        DisableGCScope,
+    },
     MMTKVMKit, Runtime, ThreadOf,
 };
 
@@ -25,17 +29,21 @@ impl<R: Runtime> Collection<MMTKVMKit<R>> for VMCollection<R> {
     where
         F: FnMut(&'static mut mmtk::Mutator<MMTKVMKit<R>>),
     {
+        let stats = &R::vmkit().gc_stats;
+        stats.gc_start::<R>();
         threads::block_all_mutators_for_gc::<R>();
         let mutators = VMActivePlan::mutators();
 
+        let mut count = 0;
         for mutator in mutators {
             mutator_visitor(mutator);
+            count += 1;
         }
+        stats.mutators_stopped::<R>(count);
     }
 
     fn is_collection_enabled() -> bool {
-        true
-        //DisableGCScope::is_gc_disabled()
+        !DisableGCScope::<R>::is_gc_disabled()
     }
 
     fn out_of_memory(tls: mmtk::util::VMThread, err_kind: mmtk::util::alloc::AllocationError) {
@@ -43,6 +51,11 @@ impl<R: Runtime> Collection<MMTKVMKit<R>> for VMCollection<R> {
     }
 
     fn resume_mutators(_tls: mmtk::util::VMWorkerThread) {
+        R::vmkit().synchronizer.deflate_idle_monitors();
+        R::vmkit().gc_stats.gc_end::<R>();
+        if *mmtkflags_verify_heap() {
+            verifier::verify_after_gc::<R>();
+        }
         threads::unblock_all_mutators_for_gc::<R>();
     }
 
@@ -51,10 +64,14 @@ impl<R: Runtime> Collection<MMTKVMKit<R>> for VMCollection<R> {
     }
 
     fn post_forwarding(_tls: mmtk::util::VMWorkerThread) {
+        R::vmkit().gc_stats.phase::<R>(GcPhase::PostForwarding);
         R::post_forwarding();
     }
 
-    fn schedule_finalization(_tls: mmtk::util::VMWorkerThread) {}
+    fn schedule_finalization(_tls: mmtk::util::VMWorkerThread) {
+        R::vmkit().gc_stats.phase::<R>(GcPhase::Finalization);
+        R::vmkit().finalization.schedule();
+    }
 
     fn spawn_gc_thread(_tls: mmtk::util::VMThread, ctx: mmtk::vm::GCThreadContext<MMTKVMKit<R>>) {
         std::thread::spawn(move || match ctx {
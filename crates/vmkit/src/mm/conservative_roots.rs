@@ -0,0 +1,100 @@
+//! # Conservative roots
+//!
+//! Conservative stack scanning built on top of VO-bit metadata. Every word on the stack of a mutator
+//! is treated as a potential object reference and is filtered through [`is_mmtk_object`](mmtk::memory_manager::is_mmtk_object).
+//! Words that pass the filter are reported as pinning roots: we can't update stack slots since we do not know
+//! whether they really contain references.
+//!
+//! Requires [`Runtime::VO_BIT`] to be set to true and `vo-bit` feature enabled.
+
+use std::{collections::HashSet, marker::PhantomData};
+
+use mmtk::{
+    util::{Address, ObjectReference, VMMutatorThread},
+    vm::{slot::Slot, RootsWorkFactory},
+};
+
+use crate::{runtime::threads::Thread, Runtime, ThreadOf};
+
+pub struct ConservativeRoots<R: Runtime> {
+    pub roots: HashSet<ObjectReference>,
+    marker: PhantomData<R>,
+}
+
+impl<R: Runtime> Default for ConservativeRoots<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: Runtime> ConservativeRoots<R> {
+    pub fn new() -> Self {
+        Self {
+            roots: HashSet::new(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Add a pointer to conservative root set.
+    ///
+    /// If pointer is not pointing to a valid object it is ignored.
+    pub fn add_pointer(&mut self, pointer: Address) {
+        if pointer.is_zero() || !pointer.is_aligned_to(ObjectReference::ALIGNMENT) {
+            return;
+        }
+
+        if let Some(object) = mmtk::memory_manager::is_mmtk_object(pointer) {
+            self.roots.insert(object);
+        }
+    }
+
+    /// Add all words in the range `[start, end)` to conservative root set.
+    ///
+    /// # Safety
+    ///
+    /// The range must be readable memory.
+    pub unsafe fn add_span(&mut self, mut start: Address, end: Address) {
+        start = start.align_up(size_of::<usize>());
+
+        while start < end {
+            let pointer = start.load::<Address>();
+            self.add_pointer(pointer);
+            start += size_of::<usize>();
+        }
+    }
+
+    /// Scan stack and saved registers of `thread`. Thread must be blocked for GC or parked.
+    pub fn add_thread(&mut self, thread: VMMutatorThread) {
+        let tls = ThreadOf::<R>::tls(thread.0);
+
+        let Some((sp, base)) = tls.saved_stack_range() else {
+            return;
+        };
+
+        unsafe {
+            for &register in tls.saved_registers() {
+                self.add_pointer(Address::from_usize(register));
+            }
+
+            self.add_span(sp, base);
+        }
+    }
+
+    /// Report collected roots to MMTk. All the objects are pinned for the duration of GC.
+    pub fn add_to_factory<SL: Slot>(&mut self, factory: &mut impl RootsWorkFactory<SL>) {
+        let roots = self.roots.drain().collect::<Vec<_>>();
+        if !roots.is_empty() {
+            factory.create_process_pinning_roots_work(roots);
+        }
+    }
+}
+
+/// Conservatively scan the stack of `thread` and report all the found objects to `factory`.
+pub fn scan_thread_stack_conservatively<R: Runtime>(
+    thread: VMMutatorThread,
+    factory: &mut impl RootsWorkFactory<R::Slot>,
+) {
+    let mut roots = ConservativeRoots::<R>::new();
+    roots.add_thread(thread);
+    roots.add_to_factory(factory);
+}
@@ -0,0 +1,182 @@
+//! # Finalization
+//!
+//! Objects whose [`GCVTable::finalize`] is not [`FinalizeCallback::None`] are registered on allocation. After each GC
+//! we check which of them became unreachable:
+//! - [`FinalizeCallback::Finalize`]: object is resurrected and put into ready queue, a dedicated finalizer thread
+//!   runs the callback later. Once finalized object is no longer tracked and is freed by the next GC that finds it dead.
+//! - [`FinalizeCallback::Drop`]: callback is invoked directly on the dead object by GC worker. It must not access
+//!   other heap objects or allocate.
+
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use mmtk::{
+    util::ObjectReference,
+    vm::{ObjectTracer, RootsWorkFactory},
+};
+use parking_lot::Mutex;
+
+use crate::{
+    mm::slot::SlotExt,
+    objectmodel::{
+        header::HeapObjectHeader,
+        vtable::{FinalizeCallback, VTable, VTablePointer},
+    },
+    runtime::threads::Thread,
+    sync::Monitor,
+    Runtime, SlotOf, ThreadOf, VTableOf,
+};
+
+pub struct Finalization<R: Runtime> {
+    /// Objects that are alive and have to be finalized once they die.
+    candidates: Mutex<Vec<ObjectReference>>,
+    /// Resurrected objects waiting for finalizer thread. These are GC roots, object being finalized
+    /// stays at the front of the queue until its callback returns.
+    ready: Monitor<VecDeque<ObjectReference>, R, true>,
+    shutdown: AtomicBool,
+}
+
+impl<R: Runtime> Finalization<R> {
+    pub fn new() -> Self {
+        Self {
+            candidates: Mutex::new(Vec::new()),
+            ready: Monitor::new(VecDeque::new()),
+            shutdown: AtomicBool::new(false),
+        }
+    }
+
+    /// Does object with `vtable` need to be registered for finalization?
+    #[inline(always)]
+    pub fn needs_finalization(vtable: VTablePointer) -> bool {
+        !matches!(
+            VTableOf::<R>::from_pointer(vtable).gc().finalize,
+            FinalizeCallback::None
+        )
+    }
+
+    /// Register `object` for finalization.
+    pub fn register(&self, object: ObjectReference) {
+        self.candidates.lock().push(object);
+    }
+
+    /// Spawn the finalizer thread. Invoked once when the main thread starts.
+    pub(crate) fn start_thread(&self) {
+        let (handle, _thread) = ThreadOf::<R>::spawn(|_thread| {
+            R::vmkit().finalization.finalizer_loop();
+        });
+        handle.expect("failed to spawn finalizer thread");
+    }
+
+    /// Number of resurrected objects whose finalizers have not completed yet.
+    pub fn pending(&self) -> usize {
+        self.ready.lock_with_handshake().len()
+    }
+
+    /// Ask finalizer thread to exit once ready queue is drained.
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        let guard = self.ready.lock_with_handshake();
+        guard.monitor.notify_all();
+        drop(guard);
+    }
+
+    fn finalizer_loop(&self) {
+        loop {
+            let mut guard = self.ready.lock_with_handshake();
+
+            while guard.is_empty() && !self.shutdown.load(Ordering::Relaxed) {
+                guard = guard.wait_with_handshake();
+            }
+
+            let Some(&object) = guard.front() else {
+                break;
+            };
+
+            drop(guard);
+
+            // object is still in the queue and thus rooted while callback runs
+            Self::run_finalize_callback(object);
+
+            self.ready.lock_with_handshake().pop_front();
+        }
+    }
+
+    fn run_finalize_callback(object: ObjectReference) {
+        let header = <&HeapObjectHeader<R>>::from(object);
+        let vt = VTableOf::<R>::from_pointer(header.vtable()).gc();
+
+        if let FinalizeCallback::Finalize(finalize) = vt.finalize {
+            finalize(object.to_raw_address().to_mut_ptr());
+        }
+    }
+
+    /// Report objects in ready queue as roots. Finalizer thread is a mutator so it is stopped
+    /// and queue is not modified until weak reference processing.
+    pub(crate) fn scan_roots(&self, factory: &mut impl RootsWorkFactory<R::Slot>) {
+        let mut ready = self.ready.lock_no_handshake();
+
+        let slots = ready
+            .iter_mut()
+            .map(|object| SlotOf::<R>::from_pointer(object as *mut ObjectReference))
+            .collect::<Vec<_>>();
+
+        drop(ready);
+
+        if !slots.is_empty() {
+            factory.create_process_roots_work(slots);
+        }
+    }
+
+    /// Process finalization candidates after transitive closure. Returns true if any object was resurrected
+    /// and transitive closure must be re-run.
+    pub(crate) fn process(&self, tracer: &mut impl ObjectTracer) -> bool {
+        let mut candidates = self.candidates.lock();
+        let mut resurrected = false;
+
+        let mut ready = self.ready.lock_no_handshake();
+
+        candidates.retain_mut(|object| {
+            if object.is_reachable() {
+                *object = object.get_forwarded_object().unwrap_or(*object);
+                return true;
+            }
+
+            let header = <&HeapObjectHeader<R>>::from(*object);
+            let vt = VTableOf::<R>::from_pointer(header.vtable()).gc();
+
+            match vt.finalize {
+                FinalizeCallback::Finalize(_) => {
+                    ready.push_back(tracer.trace_object(*object));
+                    resurrected = true;
+                }
+
+                FinalizeCallback::Drop(drop_fn) => {
+                    drop_fn(object.to_raw_address().to_mut_ptr());
+                }
+
+                FinalizeCallback::None => (),
+            }
+
+            false
+        });
+
+        resurrected
+    }
+
+    /// Wake up finalizer thread after GC.
+    pub(crate) fn schedule(&self) {
+        let guard = self.ready.lock_no_handshake();
+        if !guard.is_empty() {
+            guard.monitor.notify_all();
+        }
+        drop(guard);
+    }
+}
+
+impl<R: Runtime> Default for Finalization<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
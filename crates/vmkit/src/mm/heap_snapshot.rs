@@ -0,0 +1,276 @@
+//! # Heap snapshots
+//!
+//! Captures object graph of the heap: every object with its vtable, size and outgoing references. Snapshot
+//! can be exported in V8 `.heapsnapshot` format which is opened by Chrome DevTools (Memory tab) and other viewers.
+//!
+//! Edges are discovered with the same [`TraceCallback`] that GC uses, weak references are not reported.
+//! Heap snapshot does not know actual GC roots, objects that are not referenced by any other object are reported as roots instead.
+//! Requires VO bits, see [`for_each_object`](VMKit::for_each_object).
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Write},
+};
+
+use mmtk::{util::ObjectReference, vm::slot::Slot};
+
+use crate::{
+    mm::scanning::{Tracer, Visitor},
+    objectmodel::{
+        header::HeapObjectHeader,
+        vtable::{TraceCallback, VTable, VTablePointer},
+        ObjectModel,
+    },
+    Runtime, VMKit, VTableOf,
+};
+
+pub struct SnapshotObject {
+    pub object: ObjectReference,
+    pub vtable: VTablePointer,
+    pub name: String,
+    pub size: usize,
+    pub edges: Vec<ObjectReference>,
+}
+
+pub struct HeapSnapshot {
+    pub objects: Vec<SnapshotObject>,
+}
+
+impl<R: Runtime> VMKit<R> {
+    /// Capture snapshot of the heap. Must be invoked from a mutator thread, world is stopped while snapshot is taken.
+    pub fn heap_snapshot(&self) -> HeapSnapshot {
+        let mut objects = Vec::new();
+
+        self.for_each_object(|object| {
+            objects.push(snapshot_object::<R>(object));
+        });
+
+        HeapSnapshot { objects }
+    }
+}
+
+fn snapshot_object<R: Runtime>(object: ObjectReference) -> SnapshotObject {
+    let header = <&HeapObjectHeader<R>>::from(object);
+    let vtable = header.vtable();
+    let vt = VTableOf::<R>::from_pointer(vtable);
+
+    let name = vt
+        .name()
+        .map(str::to_owned)
+        .unwrap_or_else(|| format!("VTable@{}", vtable.0.to_address()));
+
+    let mut edges = Vec::new();
+
+    if VTableOf::<R>::VTALBE_IS_OBJECT {
+        edges.extend(VTableOf::<R>::to_object_reference(vtable));
+    }
+
+    match vt.gc().trace {
+        TraceCallback::ScanSlots(scan) => {
+            let mut sv = |slot: R::Slot| edges.extend(slot.load());
+            let mut visitor = Visitor::<R>::for_heap_walk(&mut sv, object);
+            scan(object, &mut visitor);
+        }
+
+        TraceCallback::ScanObjects(scan) => {
+            let mut sv = |objref| {
+                edges.push(objref);
+                objref
+            };
+            let mut tracer = Tracer::<R>::for_heap_walk(&mut sv, object);
+            scan(object, &mut tracer);
+        }
+
+        TraceCallback::NoTrace => (),
+    }
+
+    SnapshotObject {
+        object,
+        vtable,
+        name,
+        size: ObjectModel::<R>::bytes_used(object),
+        edges,
+    }
+}
+
+impl HeapSnapshot {
+    /// Objects that are not referenced by any other object, plus one object from each cycle that
+    /// would not be reachable otherwise.
+    fn roots(&self, index: &HashMap<ObjectReference, usize>) -> Vec<usize> {
+        let mut referenced = vec![false; self.objects.len()];
+        for object in self.objects.iter() {
+            for edge in object.edges.iter() {
+                if let Some(&ix) = index.get(edge) {
+                    referenced[ix] = true;
+                }
+            }
+        }
+
+        let mut roots = (0..self.objects.len())
+            .filter(|&ix| !referenced[ix])
+            .collect::<Vec<_>>();
+
+        let mut visited = vec![false; self.objects.len()];
+        let mut queue = roots.iter().copied().collect::<VecDeque<_>>();
+        let mut next_unvisited = 0;
+
+        loop {
+            while let Some(ix) = queue.pop_front() {
+                if std::mem::replace(&mut visited[ix], true) {
+                    continue;
+                }
+
+                for edge in self.objects[ix].edges.iter() {
+                    if let Some(&target) = index.get(edge) {
+                        queue.push_back(target);
+                    }
+                }
+            }
+
+            while next_unvisited < visited.len() && visited[next_unvisited] {
+                next_unvisited += 1;
+            }
+
+            if next_unvisited == visited.len() {
+                break;
+            }
+
+            roots.push(next_unvisited);
+            queue.push_back(next_unvisited);
+        }
+
+        roots
+    }
+
+    /// Write snapshot in V8 `.heapsnapshot` JSON format.
+    pub fn write_v8(&self, out: &mut impl Write) -> io::Result<()> {
+        const NODE_FIELDS: usize = 7;
+        const NODE_TYPE_SYNTHETIC: usize = 9;
+        const NODE_TYPE_OBJECT: usize = 3;
+        const EDGE_TYPE_ELEMENT: usize = 1;
+
+        let index = self
+            .objects
+            .iter()
+            .enumerate()
+            .map(|(ix, object)| (object.object, ix))
+            .collect::<HashMap<_, _>>();
+
+        let roots = self.roots(&index);
+
+        let mut strings = vec!["".to_owned(), "(roots)".to_owned()];
+        let mut string_ids = HashMap::new();
+        let mut intern = |name: &str| -> usize {
+            *string_ids.entry(name.to_owned()).or_insert_with(|| {
+                strings.push(name.to_owned());
+                strings.len() - 1
+            })
+        };
+
+        let names = self
+            .objects
+            .iter()
+            .map(|object| intern(&object.name))
+            .collect::<Vec<_>>();
+
+        let edge_targets = |object: &SnapshotObject| {
+            object
+                .edges
+                .iter()
+                .filter_map(|edge| index.get(edge).copied())
+                .collect::<Vec<_>>()
+        };
+        let targets = self.objects.iter().map(edge_targets).collect::<Vec<_>>();
+        let edge_count = roots.len() + targets.iter().map(Vec::len).sum::<usize>();
+
+        write!(
+            out,
+            concat!(
+                "{{\"snapshot\":{{\"meta\":{{",
+                "\"node_fields\":[\"type\",\"name\",\"id\",\"self_size\",\"edge_count\",\"trace_node_id\",\"detachedness\"],",
+                "\"node_types\":[[\"hidden\",\"array\",\"string\",\"object\",\"code\",\"closure\",\"regexp\",\"number\",\"native\",\"synthetic\",\"concatenated string\",\"sliced string\",\"symbol\",\"bigint\",\"object shape\"],",
+                "\"string\",\"number\",\"number\",\"number\",\"number\",\"number\"],",
+                "\"edge_fields\":[\"type\",\"name_or_index\",\"to_node\"],",
+                "\"edge_types\":[[\"context\",\"element\",\"property\",\"internal\",\"hidden\",\"shortcut\",\"weak\"],\"string_or_number\",\"node\"],",
+                "\"trace_function_info_fields\":[],\"trace_node_fields\":[],\"sample_fields\":[],\"location_fields\":[]}},",
+                "\"node_count\":{},\"edge_count\":{},\"trace_function_count\":0}},\n"
+            ),
+            self.objects.len() + 1,
+            edge_count
+        )?;
+
+        // node 0 is synthetic root
+        write!(
+            out,
+            "\"nodes\":[{},1,1,0,{},0,0",
+            NODE_TYPE_SYNTHETIC,
+            roots.len()
+        )?;
+        for (ix, object) in self.objects.iter().enumerate() {
+            write!(
+                out,
+                ",\n{},{},{},{},{},0,0",
+                NODE_TYPE_OBJECT,
+                names[ix],
+                (ix + 1) * 2 + 1,
+                object.size,
+                targets[ix].len()
+            )?;
+        }
+
+        write!(out, "],\n\"edges\":[")?;
+        let mut first = true;
+        let mut write_edge = |out: &mut dyn Write, index: usize, target: usize| {
+            let separator = if std::mem::replace(&mut first, false) {
+                ""
+            } else {
+                ",\n"
+            };
+            write!(
+                out,
+                "{}{},{},{}",
+                separator,
+                EDGE_TYPE_ELEMENT,
+                index,
+                (target + 1) * NODE_FIELDS
+            )
+        };
+
+        for (ix, &root) in roots.iter().enumerate() {
+            write_edge(out, ix, root)?;
+        }
+        for targets in targets.iter() {
+            for (ix, &target) in targets.iter().enumerate() {
+                write_edge(out, ix, target)?;
+            }
+        }
+
+        write!(
+            out,
+            "],\n\"trace_function_infos\":[],\"trace_tree\":[],\"samples\":[],\"locations\":[],\n\"strings\":["
+        )?;
+        for (ix, string) in strings.iter().enumerate() {
+            if ix != 0 {
+                write!(out, ",\n")?;
+            }
+            write_json_string(out, string)?;
+        }
+        write!(out, "]}}")
+    }
+}
+
+fn write_json_string(out: &mut impl Write, string: &str) -> io::Result<()> {
+    write!(out, "\"")?;
+    for c in string.chars() {
+        match c {
+            '"' => write!(out, "\\\"")?,
+            '\\' => write!(out, "\\\\")?,
+            '\n' => write!(out, "\\n")?,
+            '\r' => write!(out, "\\r")?,
+            '\t' => write!(out, "\\t")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{}", c)?,
+        }
+    }
+    write!(out, "\"")
+}
@@ -0,0 +1,67 @@
+//! # Heap walking
+//!
+//! Enumerate objects in the heap from VM code, e.g to implement "find all instances of class X"
+//! in a debugger or to collect heap statistics.
+//!
+//! Enumeration relies on VO bits: [`Runtime::VO_BIT`] must be true and `vo-bit` feature enabled. All mutators are stopped
+//! while heap is walked. Note that objects which died after the last GC are still reported until they are swept.
+
+use mmtk::util::ObjectReference;
+
+use crate::{
+    objectmodel::{header::HeapObjectHeader, vtable::VTablePointer},
+    runtime::threads::stop_the_world,
+    Runtime, VMKit,
+};
+
+/// Which objects to report while walking the heap.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ObjectFilter {
+    All,
+    /// Only objects with the given vtable.
+    VTable(VTablePointer),
+}
+
+impl ObjectFilter {
+    pub fn matches<R: Runtime>(&self, object: ObjectReference) -> bool {
+        match self {
+            Self::All => true,
+            Self::VTable(vtable) => <&HeapObjectHeader<R>>::from(object).vtable() == *vtable,
+        }
+    }
+}
+
+impl<R: Runtime> VMKit<R> {
+    /// Invoke `callback` on every object in the heap. Must be invoked from a mutator thread.
+    ///
+    /// World is stopped while `callback` runs, it must not allocate or block.
+    pub fn for_each_object(&self, callback: impl FnMut(ObjectReference)) {
+        self.for_each_object_filtered(ObjectFilter::All, callback);
+    }
+
+    /// Invoke `callback` on every object in the heap that matches `filter`. Must be invoked from a mutator thread.
+    pub fn for_each_object_filtered(
+        &self,
+        filter: ObjectFilter,
+        mut callback: impl FnMut(ObjectReference),
+    ) {
+        assert!(
+            R::VO_BIT && cfg!(feature = "vo-bit"),
+            "heap iteration requires VO bits"
+        );
+
+        stop_the_world::<R, _>(|| {
+            #[cfg(feature = "vo-bit")]
+            mmtk::memory_manager::enumerate_objects(&self.mmtk, |object| {
+                if filter.matches::<R>(object) {
+                    callback(object);
+                }
+            });
+
+            #[cfg(not(feature = "vo-bit"))]
+            {
+                let _ = &mut callback;
+            }
+        });
+    }
+}
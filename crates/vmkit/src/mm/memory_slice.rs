@@ -0,0 +1,135 @@
+//! # Memory slices
+//!
+//! [`MemorySlice`] implementation used by MMTk for bulk array operations (array copy barriers).
+//! Slice is a contiguous range of full-width reference slots, optionally inside of a heap object.
+//! Compressed references are not supported: slices can't be created once compressed heap layout is
+//! enabled, see [`ptr_compr`](crate::mm::ptr_compr).
+
+use std::{hash::Hash, marker::PhantomData};
+
+use mmtk::{
+    util::{Address, ObjectReference},
+    vm::slot::MemorySlice,
+};
+
+use crate::{mm::slot::SlotExt, Runtime, SlotOf};
+
+pub struct VMMemorySlice<R: Runtime> {
+    object: Option<ObjectReference>,
+    start: Address,
+    len: usize,
+    marker: PhantomData<R>,
+}
+
+impl<R: Runtime> VMMemorySlice<R> {
+    /// Slice of `len` reference slots starting at `start`. `object` is the object that contains slots if any.
+    ///
+    /// Panics if compressed heap layout is enabled, slots of such heap may be 32-bit wide.
+    pub fn new(object: Option<ObjectReference>, start: Address, len: usize) -> Self {
+        #[cfg(feature = "compressed-oops")]
+        assert!(
+            !crate::mm::ptr_compr::is_enabled(),
+            "memory slices only support full-width reference slots"
+        );
+        Self {
+            object,
+            start,
+            len,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<R: Runtime> Clone for VMMemorySlice<R> {
+    fn clone(&self) -> Self {
+        Self::new(self.object, self.start, self.len)
+    }
+}
+
+impl<R: Runtime> PartialEq for VMMemorySlice<R> {
+    fn eq(&self, other: &Self) -> bool {
+        self.object == other.object && self.start == other.start && self.len == other.len
+    }
+}
+
+impl<R: Runtime> Eq for VMMemorySlice<R> {}
+
+impl<R: Runtime> Hash for VMMemorySlice<R> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.object.hash(state);
+        self.start.hash(state);
+        self.len.hash(state);
+    }
+}
+
+impl<R: Runtime> std::fmt::Debug for VMMemorySlice<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "VMMemorySlice({}, {})", self.start, self.len)
+    }
+}
+
+unsafe impl<R: Runtime> Send for VMMemorySlice<R> {}
+
+pub struct VMMemorySliceIterator<R: Runtime> {
+    cursor: Address,
+    end: Address,
+    marker: PhantomData<R>,
+}
+
+impl<R: Runtime> Iterator for VMMemorySliceIterator<R> {
+    type Item = SlotOf<R>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cursor >= self.end {
+            return None;
+        }
+
+        let slot = SlotOf::<R>::from_pointer(self.cursor.to_mut_ptr::<ObjectReference>());
+        self.cursor += size_of::<ObjectReference>();
+        Some(slot)
+    }
+}
+
+impl<R: Runtime> MemorySlice for VMMemorySlice<R> {
+    type SlotType = SlotOf<R>;
+    type SlotIterator = VMMemorySliceIterator<R>;
+
+    fn iter_slots(&self) -> Self::SlotIterator {
+        VMMemorySliceIterator {
+            cursor: self.start,
+            end: self.start + self.bytes(),
+            marker: PhantomData,
+        }
+    }
+
+    fn object(&self) -> Option<ObjectReference> {
+        self.object
+    }
+
+    fn start(&self) -> Address {
+        self.start
+    }
+
+    fn bytes(&self) -> usize {
+        self.len * size_of::<ObjectReference>()
+    }
+
+    fn copy(src: &Self, tgt: &Self) {
+        debug_assert_eq!(src.len, tgt.len);
+        unsafe {
+            std::ptr::copy(
+                src.start.to_ptr::<ObjectReference>(),
+                tgt.start.to_mut_ptr::<ObjectReference>(),
+                src.len,
+            );
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! # Object pinning
+//!
+//! Temporarily prevent an already allocated object from being moved, e.g. while native code holds a raw
+//! pointer into its body. Pinning uses MMTk pin bits and requires `object-pinning` feature.
+//!
+//! - Non-moving plans (NoGC, MarkSweep, PageProtect) never move objects: pinning always succeeds and does nothing.
+//! - Immix and StickyImmix support pin bits.
+//! - Copying plans (SemiSpace, GenCopy, GenImmix) and MarkCompact cannot pin objects, [`pin`] returns [`PinError::UnsupportedPlan`].
+//!   Use [`vmkit_allocate_nonmoving`](super::vmkit_allocate_nonmoving) for such objects instead.
+
+use std::{collections::BTreeMap, marker::PhantomData};
+
+use mmtk::util::{options::PlanSelector, ObjectReference};
+use parking_lot::Mutex;
+
+use crate::Runtime;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinError {
+    /// Selected plan can't pin objects.
+    UnsupportedPlan(PlanSelector),
+    /// vmkit was built without `object-pinning` feature.
+    FeatureDisabled,
+}
+
+impl std::fmt::Display for PinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedPlan(plan) => {
+                write!(f, "plan {:?} does not support object pinning", plan)
+            }
+            Self::FeatureDisabled => write!(f, "vmkit was built without `object-pinning` feature"),
+        }
+    }
+}
+
+impl std::error::Error for PinError {}
+
+enum PinningSupport {
+    NonMoving,
+    PinBits,
+    Unsupported(PlanSelector),
+}
+
+fn pinning_support<R: Runtime>() -> PinningSupport {
+    let plan = *R::vmkit().mmtk.get_options().plan;
+    match plan {
+        PlanSelector::NoGC | PlanSelector::MarkSweep | PlanSelector::PageProtect => {
+            PinningSupport::NonMoving
+        }
+        PlanSelector::Immix | PlanSelector::StickyImmix => PinningSupport::PinBits,
+        _ => PinningSupport::Unsupported(plan),
+    }
+}
+
+/// Pin `object`. Returns `Ok(true)` if object was pinned by this call and `Ok(false)` if it is already pinned
+/// or plan never moves objects.
+pub fn pin<R: Runtime>(object: ObjectReference) -> Result<bool, PinError> {
+    match pinning_support::<R>() {
+        PinningSupport::NonMoving => Ok(false),
+        PinningSupport::Unsupported(plan) => Err(PinError::UnsupportedPlan(plan)),
+        PinningSupport::PinBits => {
+            #[cfg(feature = "object-pinning")]
+            {
+                Ok(mmtk::memory_manager::pin_object(object))
+            }
+            #[cfg(not(feature = "object-pinning"))]
+            {
+                let _ = object;
+                Err(PinError::FeatureDisabled)
+            }
+        }
+    }
+}
+
+/// Unpin `object`. Returns `Ok(true)` if object was unpinned by this call.
+pub fn unpin<R: Runtime>(object: ObjectReference) -> Result<bool, PinError> {
+    match pinning_support::<R>() {
+        PinningSupport::NonMoving => Ok(false),
+        PinningSupport::Unsupported(plan) => Err(PinError::UnsupportedPlan(plan)),
+        PinningSupport::PinBits => {
+            #[cfg(feature = "object-pinning")]
+            {
+                Ok(mmtk::memory_manager::unpin_object(object))
+            }
+            #[cfg(not(feature = "object-pinning"))]
+            {
+                let _ = object;
+                Err(PinError::FeatureDisabled)
+            }
+        }
+    }
+}
+
+/// Is `object` guaranteed to not move?
+pub fn is_pinned<R: Runtime>(object: ObjectReference) -> bool {
+    match pinning_support::<R>() {
+        PinningSupport::NonMoving => true,
+        PinningSupport::Unsupported(_) => false,
+        PinningSupport::PinBits => {
+            #[cfg(feature = "object-pinning")]
+            {
+                mmtk::memory_manager::is_pinned(object)
+            }
+            #[cfg(not(feature = "object-pinning"))]
+            {
+                let _ = object;
+                false
+            }
+        }
+    }
+}
+
+/// Number of live [`PinGuard`]s per object and whether the first of them set the pin bit. Pinned objects
+/// don't move so their references are stable keys.
+static PIN_COUNTS: Mutex<BTreeMap<ObjectReference, (usize, bool)>> = Mutex::new(BTreeMap::new());
+
+/// Keeps object pinned until dropped. Guards can be nested and overlap across threads: guards are counted per object
+/// and object is unpinned once the last guard is dropped, unless it was already pinned with [`pin`] before the first guard.
+///
+/// [`pin`] and [`unpin`] are not counted, don't [`unpin`] objects that are kept pinned by guards.
+pub struct PinGuard<R: Runtime> {
+    object: ObjectReference,
+    marker: PhantomData<*const R>,
+}
+
+impl<R: Runtime> PinGuard<R> {
+    pub fn new(object: ObjectReference) -> Result<Self, PinError> {
+        let mut counts = PIN_COUNTS.lock();
+        match counts.get_mut(&object) {
+            Some((count, _)) => *count += 1,
+            None => {
+                let unpin = pin::<R>(object)?;
+                counts.insert(object, (1, unpin));
+            }
+        }
+
+        Ok(Self {
+            object,
+            marker: PhantomData,
+        })
+    }
+
+    pub fn object(&self) -> ObjectReference {
+        self.object
+    }
+}
+
+impl<R: Runtime> Drop for PinGuard<R> {
+    fn drop(&mut self) {
+        let mut counts = PIN_COUNTS.lock();
+        let (count, unpin) = counts
+            .get_mut(&self.object)
+            .expect("pin guard is not registered");
+        *count -= 1;
+
+        if *count == 0 {
+            if *unpin {
+                let _ = unpin::<R>(self.object);
+            }
+            counts.remove(&self.object);
+        }
+    }
+}
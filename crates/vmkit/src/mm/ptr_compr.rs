@@ -1 +1,211 @@
+//! # Pointer compression
+//!
+//! Compressed object references: 32-bit offsets from the heap base shifted right by [`LOG_COMPRESSION_SHIFT`]. Since objects are
+//! at least 8-byte aligned this allows addressing up to 32 GB of heap. Compressed references are opt-in: runtime
+//! calls [`VMKitBuilder::compressed_oops`](crate::VMKitBuilder::compressed_oops), which reserves the whole MMTk heap
+//! in `[HEAP_START, HEAP_START + 32GB)` so every heap object can be compressed. Without it heap layout is left to
+//! MMTk (or to the runtime) and compressed references can't be used.
+//!
+//! Null is encoded as zero. No object can start exactly at heap base because header precedes every object reference.
 
+use std::{
+    hash::Hash,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+use mmtk::{
+    util::{
+        conversions::{chunk_align_down, chunk_align_up},
+        heap::vm_layout::VMLayout,
+        Address, ObjectReference,
+    },
+    vm::slot::{SimpleSlot, Slot},
+    MMTKBuilder,
+};
+
+use crate::{
+    mm::slot::{SlotExt, VTableSlot},
+    objectmodel::reference::BasicMember,
+    Runtime,
+};
+
+pub const LOG_COMPRESSION_SHIFT: usize = 3;
+/// Maximum heap size that can be addressed with compressed references.
+pub const MAX_COMPRESSED_HEAP_SIZE: usize = (u32::MAX as usize + 1) << LOG_COMPRESSION_SHIFT;
+/// Start of the heap reservation, 1GB so that low addresses are left for the process.
+pub const HEAP_START: usize = 0x4000_0000;
+
+/// Zero until compressed heap layout is initialized.
+static HEAP_BASE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn heap_base() -> Address {
+    unsafe { Address::from_usize(HEAP_BASE.load(Ordering::Relaxed)) }
+}
+
+/// Was heap layout for compressed references requested, see [`VMKitBuilder::compressed_oops`](crate::VMKitBuilder::compressed_oops)?
+pub fn is_enabled() -> bool {
+    !heap_base().is_zero()
+}
+
+/// Restrict MMTk heap to `[HEAP_START, HEAP_START + 32GB)` so all objects can be referenced by compressed pointers.
+pub(crate) fn initialize_heap_layout(builder: &mut MMTKBuilder) {
+    let heap_start = chunk_align_down(unsafe { Address::from_usize(HEAP_START) });
+    let heap_end = chunk_align_up(heap_start + MAX_COMPRESSED_HEAP_SIZE);
+
+    builder.set_vm_layout(VMLayout {
+        log_address_space: 36,
+        heap_start,
+        heap_end,
+        log_space_extent: 31,
+        force_use_contiguous_spaces: false,
+    });
+
+    HEAP_BASE.store(heap_start.as_usize(), Ordering::Relaxed);
+}
+
+#[inline(always)]
+pub fn compress(object: Option<ObjectReference>) -> u32 {
+    match object {
+        Some(object) => {
+            assert!(is_enabled(), "compressed heap layout is not initialized");
+            let offset = object
+                .to_raw_address()
+                .as_usize()
+                .wrapping_sub(heap_base().as_usize());
+            assert!(
+                offset < MAX_COMPRESSED_HEAP_SIZE,
+                "object {} is outside of compressed heap",
+                object
+            );
+            (offset >> LOG_COMPRESSION_SHIFT) as u32
+        }
+        None => 0,
+    }
+}
+
+#[inline(always)]
+pub fn decompress(compressed: u32) -> Option<ObjectReference> {
+    if compressed == 0 {
+        return None;
+    }
+
+    let address = heap_base() + ((compressed as usize) << LOG_COMPRESSION_SHIFT);
+    unsafe { Some(ObjectReference::from_raw_address_unchecked(address)) }
+}
+
+/// A slot that holds compressed reference.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct CompressedSlot(Address);
+
+impl CompressedSlot {
+    pub fn from_address(address: Address) -> Self {
+        Self(address)
+    }
+
+    pub fn address(&self) -> Address {
+        self.0
+    }
+
+    fn as_atomic(&self) -> &AtomicU32 {
+        unsafe { self.0.as_ref::<AtomicU32>() }
+    }
+}
+
+impl Slot for CompressedSlot {
+    fn load(&self) -> Option<ObjectReference> {
+        decompress(self.as_atomic().load(Ordering::Relaxed))
+    }
+
+    fn store(&self, object: ObjectReference) {
+        self.as_atomic()
+            .store(compress(Some(object)), Ordering::Relaxed);
+    }
+}
+
+/// A slot that can hold either full-width or compressed reference. Stacks, handles and
+/// other off-heap roots hold full-width references while heap objects can use [`CompressedMember`](crate::objectmodel::reference::CompressedMember).
+/// Also holds vtable slots for runtimes whose vtables are heap objects.
+pub enum CompressibleSlot<R: Runtime> {
+    Uncompressed(SimpleSlot),
+    Compressed(CompressedSlot),
+    VTable(VTableSlot<R>),
+}
+
+impl<R: Runtime> Clone for CompressibleSlot<R> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<R: Runtime> Copy for CompressibleSlot<R> {}
+
+impl<R: Runtime> PartialEq for CompressibleSlot<R> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Uncompressed(a), Self::Uncompressed(b)) => a == b,
+            (Self::Compressed(a), Self::Compressed(b)) => a == b,
+            (Self::VTable(a), Self::VTable(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl<R: Runtime> Eq for CompressibleSlot<R> {}
+impl<R: Runtime> Hash for CompressibleSlot<R> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Self::Uncompressed(slot) => slot.hash(state),
+            Self::Compressed(slot) => slot.hash(state),
+            Self::VTable(slot) => slot.hash(state),
+        }
+    }
+}
+
+impl<R: Runtime> std::fmt::Debug for CompressibleSlot<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Uncompressed(slot) => write!(f, "Uncompressed({:?})", slot),
+            Self::Compressed(slot) => write!(f, "{:?}", slot),
+            Self::VTable(slot) => write!(f, "{:?}", slot),
+        }
+    }
+}
+
+impl<R: Runtime> Slot for CompressibleSlot<R> {
+    fn load(&self) -> Option<ObjectReference> {
+        match self {
+            Self::Uncompressed(slot) => slot.load(),
+            Self::Compressed(slot) => slot.load(),
+            Self::VTable(slot) => slot.load(),
+        }
+    }
+
+    fn store(&self, object: ObjectReference) {
+        match self {
+            Self::Uncompressed(slot) => slot.store(object),
+            Self::Compressed(slot) => slot.store(object),
+            Self::VTable(slot) => slot.store(object),
+        }
+    }
+}
+
+impl<R: Runtime> From<CompressedSlot> for CompressibleSlot<R> {
+    fn from(slot: CompressedSlot) -> Self {
+        Self::Compressed(slot)
+    }
+}
+
+impl<R: Runtime> SlotExt<R> for CompressibleSlot<R> {
+    fn from_member<T, Tag>(member: &BasicMember<T, Tag>) -> Self {
+        Self::Uncompressed(SimpleSlot::from_address(Address::from_ptr(member)))
+    }
+
+    fn from_pointer(pointer: *mut ObjectReference) -> Self {
+        Self::Uncompressed(SimpleSlot::from_address(Address::from_ptr(pointer)))
+    }
+
+    fn from_vtable_slot(slot: VTableSlot<R>) -> Self {
+        Self::VTable(slot)
+    }
+}
@@ -0,0 +1,127 @@
+//! # Reference objects
+//!
+//! Support for Java-style soft, weak and phantom references built on top of MMTk's reference processor.
+//!
+//! Reference objects are opt-in through [`Runtime::SUPPORTS_REFERENCES`]. A reference object is an ordinary heap
+//! object with a single referent slot described by [`Runtime::referent_slot`]. Scan function of such object must not visit the referent slot, instead it registers
+//! object with [`Visitor::register_reference`](super::scanning::Visitor::register_reference) (or the tracer counterpart).
+//!
+//! MMTk then decides what happens with referent:
+//! - [`ReferenceKind::Soft`]: referent is kept alive unless GC is an emergency collection (i.e. we're about to run out of memory).
+//! - [`ReferenceKind::Weak`]: referent is cleared once it is not strongly reachable.
+//! - [`ReferenceKind::Phantom`]: referent is cleared once it is not reachable, after finalization.
+//!
+//! References whose referents were cleared are put into [`ReferenceQueue`] which is available to the VM as
+//! [`VMKit::references`](crate::VMKit::references). Objects in the queue are GC roots until they are taken out of it.
+
+use std::{collections::VecDeque, marker::PhantomData};
+
+use mmtk::{
+    util::{ObjectReference, VMWorkerThread},
+    vm::{slot::Slot, ReferenceGlue, RootsWorkFactory},
+};
+
+use crate::{mm::slot::SlotExt, sync::Monitor, MMTKVMKit, Runtime, SlotOf};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub enum ReferenceKind {
+    Soft,
+    Weak,
+    Phantom,
+}
+
+/// Register `reference` as a reference object of `kind` for current GC.
+pub fn register_reference<R: Runtime>(reference: ObjectReference, kind: ReferenceKind) {
+    const {
+        assert!(
+            R::SUPPORTS_REFERENCES,
+            "registering reference objects requires Runtime::SUPPORTS_REFERENCES"
+        )
+    };
+    let mmtk = &R::vmkit().mmtk;
+    match kind {
+        ReferenceKind::Soft => mmtk::memory_manager::add_soft_candidate(mmtk, reference),
+        ReferenceKind::Weak => mmtk::memory_manager::add_weak_candidate(mmtk, reference),
+        ReferenceKind::Phantom => mmtk::memory_manager::add_phantom_candidate(mmtk, reference),
+    }
+}
+
+pub struct ReferenceQueue<R: Runtime> {
+    queue: Monitor<VecDeque<ObjectReference>, R, true>,
+}
+
+impl<R: Runtime> ReferenceQueue<R> {
+    pub fn new() -> Self {
+        Self {
+            queue: Monitor::new(VecDeque::new()),
+        }
+    }
+
+    /// Take next enqueued reference out of the queue if there is one.
+    pub fn poll(&self) -> Option<ObjectReference> {
+        self.queue.lock_with_handshake().pop_front()
+    }
+
+    /// Take next enqueued reference out of the queue, blocking until GC enqueues one.
+    pub fn remove(&self) -> ObjectReference {
+        let mut guard = self.queue.lock_with_handshake();
+
+        loop {
+            if let Some(reference) = guard.pop_front() {
+                return reference;
+            }
+
+            guard = guard.wait_with_handshake();
+        }
+    }
+
+    /// Number of references waiting in the queue.
+    pub fn pending(&self) -> usize {
+        self.queue.lock_with_handshake().len()
+    }
+
+    /// Report enqueued references as roots.
+    pub(crate) fn scan_roots(&self, factory: &mut impl RootsWorkFactory<R::Slot>) {
+        let mut queue = self.queue.lock_no_handshake();
+
+        let slots = queue
+            .iter_mut()
+            .map(|object| SlotOf::<R>::from_pointer(object as *mut ObjectReference))
+            .collect::<Vec<_>>();
+
+        drop(queue);
+
+        if !slots.is_empty() {
+            factory.create_process_roots_work(slots);
+        }
+    }
+
+    fn enqueue(&self, references: &[ObjectReference]) {
+        let mut queue = self.queue.lock_no_handshake();
+        queue.extend(references.iter().copied());
+        queue.monitor.notify_all();
+        drop(queue);
+    }
+}
+
+pub struct VMReferenceGlue<R: Runtime>(PhantomData<R>);
+
+impl<R: Runtime> ReferenceGlue<MMTKVMKit<R>> for VMReferenceGlue<R> {
+    type FinalizableType = ObjectReference;
+
+    fn get_referent(object: ObjectReference) -> Option<ObjectReference> {
+        R::referent_slot(object).load()
+    }
+
+    fn set_referent(reff: ObjectReference, referent: ObjectReference) {
+        R::referent_slot(reff).store(referent);
+    }
+
+    fn clear_referent(new_reference: ObjectReference) {
+        R::clear_referent(new_reference);
+    }
+
+    fn enqueue_references(references: &[ObjectReference], _tls: VMWorkerThread) {
+        R::vmkit().references.enqueue(references);
+    }
+}
@@ -0,0 +1,223 @@
+//! # Allocation sampling
+//!
+//! Low-overhead allocation profiler: roughly every `alloc_sampling_interval` bytes allocated by a thread, the
+//! stack of the allocating thread is recorded together with the vtable of the allocated object. Sampling
+//! happens in [`TLAB::allocate_slow`](crate::mm::tlab::TLAB::allocate_slow) so the fast path is not affected:
+//! TLAB tracks how many bytes were bump-allocated since the last sample and each sample is weighted by that amount.
+//!
+//! Samples are aggregated by call stack and vtable and can be exported in pprof format with
+//! [`AllocationProfile::write_pprof`], then viewed with `go tool pprof` or any other pprof-compatible viewer.
+//!
+//! Stacks are walked with [`Unwinder::iter_frames`](crate::runtime::unwind::Unwinder::iter_frames) of the
+//! [process unwinder](crate::runtime::unwind::process_unwinder), only return addresses are recorded. Addresses are
+//! not symbolized. Stacks are recorded on x86-64 and AArch64, elsewhere samples have an empty stack.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+use mmtk::util::Address;
+use parking_lot::Mutex;
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use crate::runtime::unwind::{self, CacheNative, FrameAddress};
+use crate::{
+    objectmodel::vtable::{VTable, VTablePointer},
+    runtime::options::mmtkflags_alloc_sampling_interval,
+    Runtime, VMKit, VTableOf,
+};
+
+/// Maximum number of frames recorded per sample.
+pub const MAX_SAMPLE_DEPTH: usize = 64;
+
+/// Sampling interval in bytes, zero when sampling is disabled.
+#[inline(always)]
+pub fn sampling_interval() -> usize {
+    mmtkflags_alloc_sampling_interval().0
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SampleCounts {
+    pub samples: usize,
+    /// Estimated number of bytes allocated from this call stack.
+    pub bytes: usize,
+}
+
+#[derive(Default)]
+pub struct AllocationProfile {
+    samples: Mutex<HashMap<(Vec<Address>, VTablePointer), SampleCounts>>,
+}
+
+impl AllocationProfile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, stack: Vec<Address>, vtable: VTablePointer, bytes: usize) {
+        let mut samples = self.samples.lock();
+        let counts = samples.entry((stack, vtable)).or_default();
+        counts.samples += 1;
+        counts.bytes += bytes;
+    }
+
+    pub fn clear(&self) {
+        self.samples.lock().clear();
+    }
+
+    /// Aggregated samples: call stack (innermost frame first), vtable and counts.
+    pub fn samples(&self) -> Vec<(Vec<Address>, VTablePointer, SampleCounts)> {
+        self.samples
+            .lock()
+            .iter()
+            .map(|((stack, vtable), counts)| (stack.clone(), *vtable, *counts))
+            .collect()
+    }
+
+    /// Write profile as uncompressed pprof protobuf (`profile.proto`). Every sample is labeled
+    /// with `vtable`: [`VTable::name`] or vtable address.
+    pub fn write_pprof<R: Runtime>(&self, out: &mut impl Write) -> io::Result<()> {
+        let samples = self.samples();
+
+        let mut strings = vec![String::new()];
+        let mut string_ids = HashMap::new();
+        let mut intern = |string: &str| -> u64 {
+            *string_ids.entry(string.to_owned()).or_insert_with(|| {
+                strings.push(string.to_owned());
+                strings.len() as u64 - 1
+            })
+        };
+
+        let alloc_objects = intern("alloc_objects");
+        let count = intern("count");
+        let alloc_space = intern("alloc_space");
+        let bytes = intern("bytes");
+        let vtable_key = intern("vtable");
+
+        let mut profile = Vec::new();
+
+        for (typ, unit) in [(alloc_objects, count), (alloc_space, bytes)] {
+            let mut value_type = Vec::new();
+            pb::uint(&mut value_type, 1, typ);
+            pb::uint(&mut value_type, 2, unit);
+            pb::message(&mut profile, 1, &value_type);
+        }
+
+        let mut locations = HashMap::new();
+        for (stack, vtable, counts) in samples.iter() {
+            let mut location_ids = Vec::with_capacity(stack.len());
+            for address in stack.iter() {
+                let next_id = locations.len() as u64 + 1;
+                location_ids.push(*locations.entry(*address).or_insert(next_id));
+            }
+
+            let name = VTableOf::<R>::from_pointer(*vtable)
+                .name()
+                .map(str::to_owned)
+                .unwrap_or_else(|| format!("VTable@{}", vtable.0.to_address()));
+
+            let mut label = Vec::new();
+            pb::uint(&mut label, 1, vtable_key);
+            pb::uint(&mut label, 2, intern(&name));
+
+            let mut sample = Vec::new();
+            pb::packed(&mut sample, 1, &location_ids);
+            pb::packed(
+                &mut sample,
+                2,
+                &[counts.samples as u64, counts.bytes as u64],
+            );
+            pb::message(&mut sample, 3, &label);
+            pb::message(&mut profile, 2, &sample);
+        }
+
+        for (address, id) in locations.iter() {
+            let mut location = Vec::new();
+            pb::uint(&mut location, 1, *id);
+            pb::uint(&mut location, 3, address.as_usize() as u64);
+            pb::message(&mut profile, 4, &location);
+        }
+
+        for string in strings.iter() {
+            pb::bytes(&mut profile, 6, string.as_bytes());
+        }
+
+        let mut period_type = Vec::new();
+        pb::uint(&mut period_type, 1, alloc_space);
+        pb::uint(&mut period_type, 2, bytes);
+        pb::message(&mut profile, 11, &period_type);
+        pb::uint(&mut profile, 12, sampling_interval() as u64);
+
+        out.write_all(&profile)
+    }
+}
+
+impl<R: Runtime> VMKit<R> {
+    /// Allocation samples recorded so far, empty unless `alloc_sampling_interval` is set.
+    pub fn allocation_profile(&self) -> &AllocationProfile {
+        &self.allocation_profile
+    }
+}
+
+/// Record stack of the current thread for an allocation of `vtable` representing `bytes` allocated bytes.
+#[cold]
+pub(crate) fn sample<R: Runtime>(vtable: VTablePointer, bytes: usize) {
+    #[allow(unused_mut)]
+    let mut stack = Vec::new();
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    {
+        let mut cache = CacheNative::new();
+        let mut frames = unwind::process_unwinder().iter_frames(&mut cache);
+
+        while let Ok(Some(frame)) = frames.next() {
+            // first frame is this function
+            if let FrameAddress::ReturnAddress(address) = frame {
+                stack.push(unsafe { Address::from_usize(address.get() as usize) });
+                if stack.len() == MAX_SAMPLE_DEPTH {
+                    break;
+                }
+            }
+        }
+    }
+
+    R::vmkit().allocation_profile.record(stack, vtable, bytes);
+}
+
+/// Minimal protobuf encoder for `profile.proto`.
+mod pb {
+    fn varint(out: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn key(out: &mut Vec<u8>, field: u32, wire_type: u8) {
+        varint(out, ((field as u64) << 3) | wire_type as u64);
+    }
+
+    pub fn uint(out: &mut Vec<u8>, field: u32, value: u64) {
+        key(out, field, 0);
+        varint(out, value);
+    }
+
+    pub fn bytes(out: &mut Vec<u8>, field: u32, value: &[u8]) {
+        key(out, field, 2);
+        varint(out, value.len() as u64);
+        out.extend_from_slice(value);
+    }
+
+    pub fn message(out: &mut Vec<u8>, field: u32, message: &[u8]) {
+        bytes(out, field, message);
+    }
+
+    pub fn packed(out: &mut Vec<u8>, field: u32, values: &[u64]) {
+        let mut buf = Vec::new();
+        for &value in values {
+            varint(&mut buf, value);
+        }
+        bytes(out, field, &buf);
+    }
+}
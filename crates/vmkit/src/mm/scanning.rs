@@ -1,17 +1,28 @@
 use std::marker::PhantomData;
 
-use super::slot::*;
+use super::{
+    references::{register_reference, ReferenceKind},
+    slot::*,
+};
 use crate::{
-    objectmodel::{header::HeapObjectHeader, reference::*, vtable::*},
+    mm::stats::GcPhase,
+    objectmodel::{
+        ephemeron::Ephemeron,
+        header::HeapObjectHeader,
+        nanbox::{Value, ValueSlot},
+        reference::*,
+        vtable::*,
+    },
     runtime::threads::Thread,
     MMTKVMKit, Runtime, SlotOf, ThreadOf, VTableOf,
 };
 use flume::{Receiver, Sender};
 use mmtk::{
     util::{Address, ObjectReference},
-    vm::{slot::Slot, ObjectTracer, Scanning},
+    vm::{slot::Slot, ObjectTracer, ObjectTracerContext, Scanning},
     MutatorContext,
 };
+use parking_lot::Mutex;
 
 pub struct VMScanning<R: Runtime> {
     pub(crate) weak_callbacks_tx: Sender<(
@@ -22,6 +33,8 @@ pub struct VMScanning<R: Runtime> {
         ObjectReference,
         Box<dyn FnOnce(ObjectReference, &mut Tracer<R>)>,
     )>,
+    /// Addresses of ephemerons discovered in current GC whose keys are not yet known to be reachable.
+    pub(crate) ephemerons: Mutex<Vec<Address>>,
 }
 
 impl<R: Runtime> Default for VMScanning<R> {
@@ -31,6 +44,42 @@ impl<R: Runtime> Default for VMScanning<R> {
         Self {
             weak_callbacks_rx: rx,
             weak_callbacks_tx: tx,
+            ephemerons: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl<R: Runtime> VMScanning<R> {
+    /// Trace values of all ephemerons whose keys are reachable. Returns true if any value was traced,
+    /// transitive closure must be re-run then as it might make more keys reachable.
+    fn process_ephemerons(
+        &self,
+        worker: &mut mmtk::scheduler::GCWorker<MMTKVMKit<R>>,
+        tracer_context: &impl ObjectTracerContext<MMTKVMKit<R>>,
+    ) -> bool {
+        let mut progress = false;
+
+        tracer_context.with_tracer(worker, |tracer| {
+            self.ephemerons.lock().retain(|&address| {
+                let ephemeron = unsafe { address.as_mut_ref::<Ephemeron<R>>() };
+
+                if ephemeron.trace_if_key_reachable(tracer) {
+                    progress |= ephemeron.value.is_some();
+                    false
+                } else {
+                    true
+                }
+            });
+        });
+
+        progress
+    }
+
+    /// Clear all ephemerons left after reaching the fixpoint: their keys are dead.
+    fn clear_dead_ephemerons(&self) {
+        for address in self.ephemerons.lock().drain(..) {
+            let ephemeron = unsafe { address.as_mut_ref::<Ephemeron<R>>() };
+            ephemeron.clear_dead();
         }
     }
 }
@@ -66,6 +115,7 @@ impl<R: Runtime> Scanning<MMTKVMKit<R>> for VMScanning<R> {
         let mut vis = Visitor {
             sv: &mut sv as &mut dyn FnMut(R::Slot),
             source: object,
+            discover_weak_refs: true,
         };
 
         scan(object, &mut vis);
@@ -99,6 +149,7 @@ impl<R: Runtime> Scanning<MMTKVMKit<R>> for VMScanning<R> {
                     marker: PhantomData,
                     sv: &mut sv,
                     source: object,
+                    discover_weak_refs: true,
                 };
 
                 scan(object, &mut vis);
@@ -122,6 +173,7 @@ impl<R: Runtime> Scanning<MMTKVMKit<R>> for VMScanning<R> {
                 let mut vis = Visitor {
                     source: object,
                     sv: &mut sv,
+                    discover_weak_refs: true,
                 };
                 scan(object, &mut vis);
 
@@ -146,26 +198,52 @@ impl<R: Runtime> Scanning<MMTKVMKit<R>> for VMScanning<R> {
         worker: &mut mmtk::scheduler::GCWorker<MMTKVMKit<R>>,
         tracer_context: impl mmtk::vm::ObjectTracerContext<MMTKVMKit<R>>,
     ) -> bool {
+        R::vmkit().gc_stats.phase::<R>(GcPhase::WeakReferences);
+        let scanning = &R::vmkit().scanning;
+
+        // (1) Ephemerons go first: weak references must not be cleared while there's
+        // still a chance that their referents are reachable through ephemeron values.
+        if scanning.process_ephemerons(worker, &tracer_context) {
+            return true;
+        }
+
         let mut rescan = false;
 
+        // (2) One-shot weak callbacks.
         tracer_context.with_tracer(worker, |tracer| {
             let mut v = |objref| {
                 rescan = true;
                 tracer.trace_object(objref)
             };
-            for (obj, weak_callback) in R::vmkit().scanning.weak_callbacks_rx.drain() {
+            for (obj, weak_callback) in scanning.weak_callbacks_rx.drain() {
                 weak_callback(
                     obj,
                     &mut Tracer {
                         marker: PhantomData,
                         sv: &mut v,
                         source: obj,
+                        discover_weak_refs: true,
                     },
                 );
             }
         });
 
-        R::process_weak_refs(worker, tracer_context) || rescan
+        // (3) Finalization, might resurrect objects.
+        tracer_context.with_tracer(worker, |tracer| {
+            rescan |= R::vmkit().finalization.process(tracer);
+        });
+
+        let runtime_rescan = R::process_weak_refs(worker, tracer_context);
+
+        if rescan || runtime_rescan {
+            return true;
+        }
+
+        // (4) Nothing else can become reachable, keys of remaining ephemerons are dead.
+        scanning.clear_dead_ephemerons();
+        R::vmkit().synchronizer.process_weak_monitors();
+
+        false
     }
 
     fn scan_roots_in_mutator_thread(
@@ -176,13 +254,38 @@ impl<R: Runtime> Scanning<MMTKVMKit<R>> for VMScanning<R> {
         let tls = mutator.get_tls();
         mutator.flush();
 
+        #[cfg(feature = "vo-bit")]
+        if R::CONSERVATIVE_STACK_SCAN {
+            let mut factory = factory.clone();
+            crate::mm::conservative_roots::scan_thread_stack_conservatively::<R>(tls, &mut factory);
+        }
+
+        if R::SCAN_JIT_FRAMES {
+            let mut factory = factory.clone();
+            crate::mm::stack_map::scan_thread_frames::<R>(tls, &mut factory);
+        }
+
+        if let Some(shadow_stack) = ThreadOf::<R>::tls(tls.0).shadow_stack_opt() {
+            let mut factory = factory.clone();
+            shadow_stack.scan_roots(&mut factory);
+        }
+
+        unsafe {
+            let mut factory = factory.clone();
+            ThreadOf::<R>::tls(tls.0)
+                .lock_stack_mut_unchecked()
+                .scan_roots(&mut factory);
+        }
+
         ThreadOf::<R>::scan_roots(tls, factory);
     }
 
     fn scan_vm_specific_roots(
         _tls: mmtk::util::VMWorkerThread,
-        factory: impl mmtk::vm::RootsWorkFactory<<MMTKVMKit<R> as mmtk::vm::VMBinding>::VMSlot>,
+        mut factory: impl mmtk::vm::RootsWorkFactory<<MMTKVMKit<R> as mmtk::vm::VMBinding>::VMSlot>,
     ) {
+        R::vmkit().finalization.scan_roots(&mut factory);
+        R::vmkit().references.scan_roots(&mut factory);
         R::scan_roots(factory);
     }
 
@@ -194,9 +297,20 @@ impl<R: Runtime> Scanning<MMTKVMKit<R>> for VMScanning<R> {
 pub struct Visitor<'a, R: Runtime> {
     sv: &'a mut dyn FnMut(R::Slot),
     source: ObjectReference,
+    /// False when object is scanned outside of GC (e.g heap snapshot), weak references are ignored then.
+    discover_weak_refs: bool,
 }
 
 impl<'a, R: Runtime> Visitor<'a, R> {
+    /// Visitor for scanning `source` outside of GC. Weak references are not discovered.
+    pub(crate) fn for_heap_walk(sv: &'a mut dyn FnMut(R::Slot), source: ObjectReference) -> Self {
+        Self {
+            sv,
+            source,
+            discover_weak_refs: false,
+        }
+    }
+
     pub fn visit_member<T, Tag: 'static>(&mut self, member: &BasicMember<T, Tag>) {
         if std::any::TypeId::of::<Tag>() == std::any::TypeId::of::<StrongMemberTag>() {
             let slot = member.slot::<R>();
@@ -221,14 +335,91 @@ impl<'a, R: Runtime> Visitor<'a, R> {
         }
     }
 
+    /// Same as [`visit_member`](Self::visit_member) but for compressed members.
+    #[cfg(feature = "compressed-oops")]
+    pub fn visit_compressed_member<T, Tag: 'static>(&mut self, member: &CompressedMember<T, Tag>)
+    where
+        R::Slot: From<crate::mm::ptr_compr::CompressedSlot>,
+    {
+        if std::any::TypeId::of::<Tag>() == std::any::TypeId::of::<StrongMemberTag>() {
+            let slot = member.slot::<R>();
+            (self.sv)(slot);
+        } else if std::any::TypeId::of::<Tag>() == std::any::TypeId::of::<WeakMemberTag>() {
+            let offset = Address::from_ref(member) - self.source.to_raw_address();
+
+            self.register_weak_callback(Box::new(move |objref, _tracer| unsafe {
+                let raw = objref.to_raw_address();
+                let field = raw + offset;
+                let member = field.as_mut_ref::<CompressedMember<T, WeakMemberTag>>();
+
+                if let Some(objref) = member
+                    .object_reference()
+                    .filter(|objref| objref.is_reachable())
+                {
+                    member.write(Some(objref.get_forwarded_object().unwrap_or(objref)));
+                } else {
+                    member.write(None);
+                }
+            }));
+        }
+    }
+
     pub fn visit_slot(&mut self, slot: R::Slot) {
         (self.sv)(slot);
     }
 
+    /// Visit a NaN-boxed value. Only values holding object references are reported.
+    pub fn visit_value(&mut self, value: &Value)
+    where
+        R::Slot: From<ValueSlot<R>>,
+    {
+        if value.is_object() {
+            (self.sv)(ValueSlot::<R>::from_value(value).into());
+        }
+    }
+
+    /// Visit a word tagged according to `S`. Only words holding object references are reported.
+    pub fn visit_tagged<S: TagScheme>(&mut self, word: &usize)
+    where
+        R::Slot: From<TaggedSlot<R, S>>,
+    {
+        if S::decode(*word).is_some() {
+            (self.sv)(TaggedSlot::<R, S>::from_address(Address::from_ref(word)).into());
+        }
+    }
+
+    /// Register an ephemeron located in the object that is being scanned. Its value is traced once
+    /// key is known to be reachable.
+    pub fn register_ephemeron(&mut self, ephemeron: &Ephemeron<R>) {
+        if !self.discover_weak_refs {
+            return;
+        }
+
+        R::vmkit()
+            .scanning
+            .ephemerons
+            .lock()
+            .push(Address::from_ref(ephemeron));
+    }
+
+    /// Register the object that is being scanned as a reference object of `kind`. Referent slot of the object
+    /// must not be visited.
+    pub fn register_reference(&mut self, kind: ReferenceKind) {
+        if !self.discover_weak_refs {
+            return;
+        }
+
+        register_reference::<R>(self.source, kind);
+    }
+
     pub fn register_weak_callback(
         &mut self,
         callback: Box<dyn FnOnce(ObjectReference, &mut Tracer<R>)>,
     ) {
+        if !self.discover_weak_refs {
+            return;
+        }
+
         R::vmkit()
             .scanning
             .weak_callbacks_tx
@@ -241,10 +432,25 @@ impl<'a, R: Runtime> Visitor<'a, R> {
 pub struct Tracer<'a, R: Runtime> {
     sv: &'a mut dyn FnMut(ObjectReference) -> ObjectReference,
     source: ObjectReference,
+    /// False when object is traced outside of GC (e.g heap snapshot), weak references are ignored then.
+    discover_weak_refs: bool,
     marker: PhantomData<R>,
 }
 
 impl<'a, R: Runtime> Tracer<'a, R> {
+    /// Tracer for tracing `source` outside of GC. Weak references are not discovered.
+    pub(crate) fn for_heap_walk(
+        sv: &'a mut dyn FnMut(ObjectReference) -> ObjectReference,
+        source: ObjectReference,
+    ) -> Self {
+        Self {
+            sv,
+            source,
+            discover_weak_refs: false,
+            marker: PhantomData,
+        }
+    }
+
     pub fn trace_member<'gc, T, Tag: 'static>(
         &mut self,
         member: BasicMember<'gc, T, Tag>,
@@ -265,15 +471,79 @@ impl<'a, R: Runtime> Tracer<'a, R> {
         }
     }
 
+    /// Same as [`trace_member`](Self::trace_member) but for compressed members.
+    #[cfg(feature = "compressed-oops")]
+    pub fn trace_compressed_member<'gc, T, Tag: 'static>(
+        &mut self,
+        member: CompressedMember<'gc, T, Tag>,
+    ) -> CompressedMember<'gc, T, Tag> {
+        if std::any::TypeId::of::<Tag>() == std::any::TypeId::of::<StrongMemberTag>() {
+            if let Some(objref) = member.object_reference() {
+                CompressedMember::from_object_reference(Some((self.sv)(objref)))
+            } else {
+                member
+            }
+        } else if std::any::TypeId::of::<Tag>() == std::any::TypeId::of::<WeakMemberTag>() {
+            panic!(
+                "Cannot trace weak member, use `Visitor` or `register_weak_callback` on your own"
+            );
+        } else {
+            // untraced object: skip
+            member
+        }
+    }
+
     pub fn trace_object_reference(&mut self, objref: ObjectReference) -> ObjectReference {
         (self.sv)(objref)
     }
 
+    /// Trace a NaN-boxed value, value is updated in place if object it references has moved.
+    pub fn trace_value(&mut self, value: &mut Value) {
+        if let Some(objref) = value.as_object() {
+            *value = Value::new_object((self.sv)(objref));
+        }
+    }
+
+    /// Trace a word tagged according to `S`, word is updated in place if object it references has moved.
+    pub fn trace_tagged<S: TagScheme>(&mut self, word: &mut usize) {
+        if let Some(objref) = S::decode(*word) {
+            *word = S::encode(*word, (self.sv)(objref));
+        }
+    }
+
+    /// Register an ephemeron located in the object that is being traced. Its value is traced once
+    /// key is known to be reachable.
+    pub fn register_ephemeron(&mut self, ephemeron: &Ephemeron<R>) {
+        if !self.discover_weak_refs {
+            return;
+        }
+
+        R::vmkit()
+            .scanning
+            .ephemerons
+            .lock()
+            .push(Address::from_ref(ephemeron));
+    }
+
+    /// Register the object that is being traced as a reference object of `kind`. Referent of the object
+    /// must not be traced.
+    pub fn register_reference(&mut self, kind: ReferenceKind) {
+        if !self.discover_weak_refs {
+            return;
+        }
+
+        register_reference::<R>(self.source, kind);
+    }
+
     pub fn register_weak_callback(
         &mut self,
         object: ObjectReference,
         callback: Box<dyn FnOnce(ObjectReference, &mut Tracer<R>)>,
     ) {
+        if !self.discover_weak_refs {
+            return;
+        }
+
         R::vmkit()
             .scanning
             .weak_callbacks_tx
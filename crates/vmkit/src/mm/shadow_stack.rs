@@ -1,102 +1,165 @@
-use std::{
-    alloc::Layout,
-    marker::PhantomData,
-    mem::MaybeUninit,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
+//! # Shadow stacks
+//!
+//! Precise roots for interpreters and other VM code that can't produce stack maps. Each mutator thread gets a
+//! [`ShadowStack`] from [`ShadowStackPool`] when its [`TLSData`](crate::runtime::threads::TLSData) is created,
+//! local variables holding heap references are registered in it with [`shadow_frame!`](crate::shadow_frame) and
+//! [`RootsFrame`]. Shadow stack of every mutator is scanned automatically during GC, no [`Thread::scan_roots`](crate::runtime::threads::Thread::scan_roots)
+//! implementation is required for them.
+//!
+//! Shadow stack stores slots pointing to the registered variables, so moving GC updates variables in place.
+
+use std::cell::UnsafeCell;
+
+use mmtk::{
+    util::{Address, ObjectReference},
+    vm::RootsWorkFactory,
 };
+use parking_lot::Mutex;
 
-use mmtk::util::ObjectReference;
-
-use crate::{Runtime, SlotOf};
+use crate::{
+    objectmodel::nanbox::{Value, ValueSlot},
+    Runtime, SlotOf,
+};
 
-use super::slot::SlotExt;
+use super::slot::{SlotExt, TagScheme, Tagged, TaggedSlot};
 
 pub trait Rootable<R: Runtime> {
     /// Convert this rootable value to slot which holds any heap objects.
     fn to_slot(&mut self) -> SlotOf<R>;
 }
 
+/// Number of entries shadow stack is created with.
+pub const INITIAL_SHADOW_STACK_ENTRIES: usize = 1024;
+/// Shadow stack grows on demand up to this number of entries, exceeding it is a shadow stack overflow.
+pub const MAX_SHADOW_STACK_ENTRIES: usize = 1024 * 1024;
+
 /// A pool of shadow-stacks for threads to use. This type is thread-safe and
 /// is accessed by multiple threads in order to acquire shadow stacks.
-pub struct ShadowStackPool {}
+pub struct ShadowStackPool<R: Runtime> {
+    free: Mutex<Vec<Box<ShadowStack<R>>>>,
+}
+
+impl<R: Runtime> ShadowStackPool<R> {
+    pub fn new() -> Self {
+        Self {
+            free: Mutex::new(Vec::new()),
+        }
+    }
 
-pub type ShadowStackRef<R, T> = Arc<ShadowStack<R, T>>;
+    /// Acquire an empty shadow stack, reusing one released by a terminated thread if possible.
+    pub fn acquire(&self) -> Box<ShadowStack<R>> {
+        self.free
+            .lock()
+            .pop()
+            .unwrap_or_else(|| Box::new(ShadowStack::new()))
+    }
 
-#[repr(C)]
-pub struct ShadowStack<R: Runtime, T: Rootable<R>> {
-    pub base: AtomicUsize,
-    pub top: AtomicUsize,
-    marker: PhantomData<(&'static R, *mut T)>,
+    /// Return shadow stack to the pool. All its frames must be left.
+    pub fn release(&self, shadow_stack: Box<ShadowStack<R>>) {
+        assert!(
+            shadow_stack.is_empty(),
+            "shadow stack is released with active frames"
+        );
+        self.free.lock().push(shadow_stack);
+    }
 }
 
-#[repr(C)]
-pub struct RootsFrame<'a, R: Runtime, T: Rootable<R> + 'a> {
-    pub shadow_stack: &'a ShadowStack<R, T>,
+/// Stack of slots registered by [`RootsFrame`]s. Mutated only by the owning thread, GC reads it only
+/// while the owner is blocked.
+pub struct ShadowStack<R: Runtime> {
+    entries: UnsafeCell<Vec<Option<SlotOf<R>>>>,
+}
+
+unsafe impl<R: Runtime> Send for ShadowStack<R> {}
+unsafe impl<R: Runtime> Sync for ShadowStack<R> {}
+
+pub struct RootsFrame<'a, R: Runtime> {
+    pub shadow_stack: &'a ShadowStack<R>,
+    start: usize,
     num_roots: usize,
-    top: usize,
 }
 
-impl<'a, R: Runtime, T: Rootable<R> + 'a> RootsFrame<'a, R, T> {
+impl<'a, R: Runtime> RootsFrame<'a, R> {
     /// Save reference to `T` on shadow-stack frame. Must be valid for entire lifetime of the
     /// frame.
-    pub fn save_root(&self, index: usize, value: &'a mut T) {
-        assert!(index < self.num_roots, "Too many roots");
-        unsafe {
-            let p = self.top as *mut &'a mut T;
-
-            p.add(index).write(value);
-        }
+    pub fn save_root<T: Rootable<R>>(&self, index: usize, value: &'a mut T) {
+        unsafe { self.save_root_unchecked(index, value) }
     }
 
-    /*pub fn restore_root(&self, index: usize, value: &mut T) {
+    /// Same as [`save_root`](Self::save_root) but `value` stays accessible while frame is alive.
+    ///
+    /// # Safety
+    ///
+    /// `value` must not be moved or go out of scope before the frame is left.
+    pub unsafe fn save_root_unchecked<T: Rootable<R>>(&self, index: usize, value: *mut T) {
         assert!(index < self.num_roots, "Too many roots");
-        unsafe {
-            let p = self.top as *mut T;
-            *value = p.add(index).read();
-        }
-    }*/
+        (*self.shadow_stack.entries.get())[self.start + index] = Some((*value).to_slot());
+    }
 }
 
-impl<R: Runtime, T: Rootable<R>> ShadowStack<R, T> {
+impl<R: Runtime> ShadowStack<R> {
     pub fn new() -> Self {
-        unsafe {
-            let mem = std::alloc::alloc_zeroed(Layout::array::<T>(16 * 1024).unwrap());
-
-            Self {
-                base: AtomicUsize::new(mem as _),
-                top: AtomicUsize::new(mem as _),
-                marker: PhantomData,
-            }
+        Self {
+            entries: UnsafeCell::new(Vec::with_capacity(INITIAL_SHADOW_STACK_ENTRIES)),
         }
     }
 
-    pub fn enter_roots_frame<'a>(&'a self, num_roots: usize) -> RootsFrame<'a, R, T> {
-        let top = self
-            .top
-            .fetch_add(num_roots * size_of::<T>(), Ordering::Relaxed);
+    /// Number of entries in all active frames.
+    pub fn len(&self) -> usize {
+        unsafe { (*self.entries.get()).len() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Enter a frame of `num_roots` entries. Panics on shadow stack overflow.
+    pub fn enter_roots_frame<'a>(&'a self, num_roots: usize) -> RootsFrame<'a, R> {
+        let entries = unsafe { &mut *self.entries.get() };
+        let start = entries.len();
+
+        assert!(
+            num_roots <= MAX_SHADOW_STACK_ENTRIES - start,
+            "shadow stack overflow: {} entries requested with {} in use",
+            num_roots,
+            start
+        );
+
+        entries.resize(start + num_roots, None);
         RootsFrame {
             shadow_stack: self,
+            start,
             num_roots,
-            top,
         }
     }
 
-    pub fn leave_roots_frame(frame: &RootsFrame<'_, R, T>) {
-        frame.shadow_stack.top.store(frame.top, Ordering::Relaxed);
-        unsafe {
-            let p = frame.top as *mut MaybeUninit<T>;
+    pub fn leave_roots_frame(frame: &RootsFrame<'_, R>) {
+        let entries = unsafe { &mut *frame.shadow_stack.entries.get() };
+        debug_assert_eq!(
+            entries.len(),
+            frame.start + frame.num_roots,
+            "roots frames must be left in LIFO order"
+        );
+        entries.truncate(frame.start);
+    }
 
-            for i in 0..frame.num_roots {
-                p.add(i).write(MaybeUninit::zeroed());
-            }
+    /// Report all registered slots to `factory`. Owner thread must be blocked.
+    pub fn scan_roots(&self, factory: &mut impl RootsWorkFactory<SlotOf<R>>) {
+        let slots = unsafe {
+            (*self.entries.get())
+                .iter()
+                .flatten()
+                .copied()
+                .collect::<Vec<_>>()
+        };
+
+        if !slots.is_empty() {
+            factory.create_process_roots_work(slots);
         }
     }
 }
 
-impl<'a, R: Runtime, T: Rootable<R>> Drop for RootsFrame<'a, R, T> {
+impl<'a, R: Runtime> Drop for RootsFrame<'a, R> {
     fn drop(&mut self) {
         ShadowStack::leave_roots_frame(self);
     }
@@ -104,21 +167,13 @@ impl<'a, R: Runtime, T: Rootable<R>> Drop for RootsFrame<'a, R, T> {
 
 #[macro_export]
 macro_rules! count {
-    ($var: ident, $($rest:tt)*) => {
-        count!(@count 1; $($rest)*)
-    };
-
-    (@count $c: expr;) => {
-        $c
+    () => {
+        0usize
     };
 
-    (@count $c: expr; $var:ident, $($rest:tt)*) => {
-        count!(@count $c + 1; $($rest)*);
+    ($var: ident $(, $rest: ident)*) => {
+        1usize + $crate::count!($($rest),*)
     };
-
-    () => {
-        0
-    }
 }
 
 /// Create a shadow-stack frame and execute expression `$e` inside of it.
@@ -126,33 +181,39 @@ macro_rules! count {
 /// This macro will put all variables into the shadow-stack `$shadow_stack`
 /// and then restore them once the frame is expired.
 ///
+/// # Safety
+///
+/// Macro expands to [`RootsFrame::save_root_unchecked`] calls and must be invoked inside of `unsafe` block.
+/// Every `$var` must be a local declared before the macro and `$e` must not move out of it: GC updates the variables
+/// through the frame while `$e` runs.
+///
 /// Example:
-/// ```rust,must_fail
+/// ```rust,ignore
 /// let mut x = ...;
-/// // shadow stack which holds roots of ObjectReference type.
-/// let mut stack = ShadowStack::<MyRuntime, ObjectReference>::new();
-/// shadow_frame!(stack => x : gc());
+/// // shadow stack of the current thread
+/// let stack = vmkit_get_tls::<MyRuntime>().shadow_stack();
+/// unsafe { shadow_frame!(stack => x : gc()) };
 /// /* x is still alive here */
 /// ```
 #[macro_export]
 macro_rules! shadow_frame {
-    ($shadow_stack: expr => $($var: ident),* : $e: expr) => {
-        let num_roots = count!($($var),*);
-
-        {
-            let frame = $shadow_stack.enter_roots_frame(num_roots);
-            let mut ix = 0;
-            $(
-                frame.save_root(ix, &mut $var);
-                ix += 1;
-            )*
-            let result = $e;
-
-            drop(frame);
-
-            result
-        }
-    };
+    ($shadow_stack: expr => $($var: ident),* : $e: expr) => {{
+        let num_roots = $crate::count!($($var),*);
+
+        let frame = $shadow_stack.enter_roots_frame(num_roots);
+        let mut ix = 0;
+        $(
+            // caller guarantees that variables outlive the frame
+            frame.save_root_unchecked(ix, &mut $var);
+            ix += 1;
+        )*
+        let _ = ix;
+        let result = $e;
+
+        drop(frame);
+
+        result
+    }};
 }
 
 impl<R: Runtime> Rootable<R> for ObjectReference {
@@ -160,3 +221,33 @@ impl<R: Runtime> Rootable<R> for ObjectReference {
         <SlotOf<R> as SlotExt<R>>::from_pointer(self as *const Self as *mut ObjectReference)
     }
 }
+
+impl<R: Runtime> Rootable<R> for Value
+where
+    SlotOf<R>: From<ValueSlot<R>>,
+{
+    fn to_slot(&mut self) -> SlotOf<R> {
+        ValueSlot::<R>::from_value(self).into()
+    }
+}
+
+impl<R: Runtime, S: TagScheme> Rootable<R> for Tagged<S>
+where
+    SlotOf<R>: From<TaggedSlot<R, S>>,
+{
+    fn to_slot(&mut self) -> SlotOf<R> {
+        TaggedSlot::from_address(Address::from_mut_ptr(self)).into()
+    }
+}
+
+impl<R: Runtime> Default for ShadowStack<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: Runtime> Default for ShadowStackPool<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
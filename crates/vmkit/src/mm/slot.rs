@@ -1,9 +1,13 @@
-use std::{hash::Hash, marker::PhantomData};
+use std::{
+    hash::Hash,
+    marker::PhantomData,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use crate::{objectmodel::vtable::*, MMTKVMKit};
 use mmtk::{
     util::{Address, ObjectReference},
-    vm::slot::Slot,
+    vm::slot::{SimpleSlot, Slot},
 };
 
 use crate::{
@@ -88,3 +92,234 @@ impl<R: Runtime> Slot for VTableSlot<R> {
         }
     }
 }
+
+/// Describes how a runtime stores object references in tagged words, e.g small integers and pointers
+/// distinguished by low bits (Smalltalk, OCaml, Lisp fixnums). Used by [`TaggedSlot`] to load and store
+/// tagged fields in place.
+pub trait TagScheme: 'static + Send + Sync {
+    /// Object reference stored in `word`, `None` if `word` holds an immediate value.
+    fn decode(word: usize) -> Option<ObjectReference>;
+    /// Word that stores `object` in place of `old`. `old` is the current content of the slot and always
+    /// decodes to an object, scheme can use it to keep tag bits it does not own.
+    fn encode(old: usize, object: ObjectReference) -> usize;
+}
+
+/// Tag scheme where an object reference is a word whose bits in `MASK` are equal to `TAG`. Objects
+/// must be aligned so that these bits are zero in their address.
+///
+/// OCaml-like layout where immediates have low bit set is `LowBitsTagScheme<1, 0>`, V8-like layout
+/// where pointers have low bit set is `LowBitsTagScheme<1, 1>`.
+pub struct LowBitsTagScheme<const MASK: usize, const TAG: usize>;
+
+impl<const MASK: usize, const TAG: usize> TagScheme for LowBitsTagScheme<MASK, TAG> {
+    fn decode(word: usize) -> Option<ObjectReference> {
+        if word & MASK != TAG {
+            return None;
+        }
+
+        ObjectReference::from_raw_address(unsafe { Address::from_usize(word & !MASK) })
+    }
+
+    fn encode(old: usize, object: ObjectReference) -> usize {
+        let address = object.to_raw_address().as_usize();
+        debug_assert!(
+            address & MASK == 0,
+            "object {} is not aligned for tag mask {:#x}",
+            object,
+            MASK
+        );
+        address | (old & MASK)
+    }
+}
+
+/// A slot that holds a word tagged according to `S`. Loads `None` for immediate values.
+///
+/// Tagged fields are reported with [`Visitor::visit_tagged`](crate::mm::scanning::Visitor::visit_tagged), tagged locals
+/// are rooted on the shadow stack as [`Tagged`].
+pub struct TaggedSlot<R: Runtime, S: TagScheme>(Address, PhantomData<(R, S)>);
+
+impl<R: Runtime, S: TagScheme> TaggedSlot<R, S> {
+    pub fn from_address(address: Address) -> Self {
+        Self(address, PhantomData)
+    }
+
+    pub fn from_pointer(pointer: *mut usize) -> Self {
+        Self(Address::from_mut_ptr(pointer), PhantomData)
+    }
+
+    pub const fn address(&self) -> Address {
+        self.0
+    }
+
+    fn as_atomic(&self) -> &AtomicUsize {
+        unsafe { self.0.as_ref::<AtomicUsize>() }
+    }
+}
+
+impl<R: Runtime, S: TagScheme> Clone for TaggedSlot<R, S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<R: Runtime, S: TagScheme> Copy for TaggedSlot<R, S> {}
+
+impl<R: Runtime, S: TagScheme> PartialEq for TaggedSlot<R, S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<R: Runtime, S: TagScheme> Eq for TaggedSlot<R, S> {}
+impl<R: Runtime, S: TagScheme> Hash for TaggedSlot<R, S> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl<R: Runtime, S: TagScheme> std::fmt::Debug for TaggedSlot<R, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TaggedSlot({})", self.0)
+    }
+}
+
+impl<R: Runtime, S: TagScheme> Slot for TaggedSlot<R, S> {
+    fn load(&self) -> Option<ObjectReference> {
+        S::decode(self.as_atomic().load(Ordering::Relaxed))
+    }
+
+    fn store(&self, object: ObjectReference) {
+        let atomic = self.as_atomic();
+        let old = atomic.load(Ordering::Relaxed);
+        atomic.store(S::encode(old, object), Ordering::Relaxed);
+    }
+}
+
+/// A word tagged according to `S`, e.g a local variable of an interpreter. Can be registered on the shadow stack
+/// as a root, GC updates the word in place.
+#[repr(transparent)]
+pub struct Tagged<S: TagScheme>(usize, PhantomData<S>);
+
+impl<S: TagScheme> Tagged<S> {
+    pub const fn new(word: usize) -> Self {
+        Self(word, PhantomData)
+    }
+
+    pub const fn word(self) -> usize {
+        self.0
+    }
+
+    /// Object reference stored in the word, `None` if it holds an immediate value.
+    pub fn object(self) -> Option<ObjectReference> {
+        S::decode(self.0)
+    }
+}
+
+impl<S: TagScheme> Clone for Tagged<S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S: TagScheme> Copy for Tagged<S> {}
+
+impl<S: TagScheme> PartialEq for Tagged<S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<S: TagScheme> Eq for Tagged<S> {}
+
+impl<S: TagScheme> std::fmt::Debug for Tagged<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Tagged({:#x})", self.0)
+    }
+}
+
+/// A slot that can hold either a plain object reference or a word tagged according to `S`. Runtimes that
+/// use tagged fields can use it as [`Runtime::Slot`] and report tagged fields with [`TaggedOrObjectSlot::Tagged`].
+pub enum TaggedOrObjectSlot<R: Runtime, S: TagScheme> {
+    Object(SimpleSlot),
+    Tagged(TaggedSlot<R, S>),
+    VTable(VTableSlot<R>),
+}
+
+impl<R: Runtime, S: TagScheme> Clone for TaggedOrObjectSlot<R, S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<R: Runtime, S: TagScheme> Copy for TaggedOrObjectSlot<R, S> {}
+
+impl<R: Runtime, S: TagScheme> PartialEq for TaggedOrObjectSlot<R, S> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Object(a), Self::Object(b)) => a == b,
+            (Self::Tagged(a), Self::Tagged(b)) => a == b,
+            (Self::VTable(a), Self::VTable(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl<R: Runtime, S: TagScheme> Eq for TaggedOrObjectSlot<R, S> {}
+impl<R: Runtime, S: TagScheme> Hash for TaggedOrObjectSlot<R, S> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Self::Object(slot) => slot.hash(state),
+            Self::Tagged(slot) => slot.hash(state),
+            Self::VTable(slot) => slot.hash(state),
+        }
+    }
+}
+
+impl<R: Runtime, S: TagScheme> std::fmt::Debug for TaggedOrObjectSlot<R, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Object(slot) => write!(f, "Object({:?})", slot),
+            Self::Tagged(slot) => write!(f, "{:?}", slot),
+            Self::VTable(slot) => write!(f, "{:?}", slot),
+        }
+    }
+}
+
+impl<R: Runtime, S: TagScheme> Slot for TaggedOrObjectSlot<R, S> {
+    fn load(&self) -> Option<ObjectReference> {
+        match self {
+            Self::Object(slot) => slot.load(),
+            Self::Tagged(slot) => slot.load(),
+            Self::VTable(slot) => slot.load(),
+        }
+    }
+
+    fn store(&self, object: ObjectReference) {
+        match self {
+            Self::Object(slot) => slot.store(object),
+            Self::Tagged(slot) => slot.store(object),
+            Self::VTable(slot) => slot.store(object),
+        }
+    }
+}
+
+impl<R: Runtime, S: TagScheme> From<TaggedSlot<R, S>> for TaggedOrObjectSlot<R, S> {
+    fn from(slot: TaggedSlot<R, S>) -> Self {
+        Self::Tagged(slot)
+    }
+}
+
+impl<R: Runtime, S: TagScheme> SlotExt<R> for TaggedOrObjectSlot<R, S> {
+    fn from_member<T, Tag>(member: &BasicMember<T, Tag>) -> Self {
+        Self::Object(SimpleSlot::from_address(Address::from_ptr(member)))
+    }
+
+    fn from_pointer(pointer: *mut ObjectReference) -> Self {
+        Self::Object(SimpleSlot::from_address(Address::from_ptr(pointer)))
+    }
+
+    fn from_vtable_slot(slot: VTableSlot<R>) -> Self {
+        Self::VTable(slot)
+    }
+}
@@ -0,0 +1,248 @@
+//! # Stack maps
+//!
+//! Precise root information for JIT-compiled frames. For each call-site JIT code records a [`StackMap`]
+//! keyed by the return address of the call: which slots of the frame (relative to frame pointer) and which
+//! callee-saved registers hold object references while the call is in progress.
+//!
+//! When thread is scanned for roots its stack is walked with [`Unwinder`](crate::runtime::unwind::Unwinder), starting
+//! at the state thread saved when it blocked. Every frame whose return address has a stack map gets its stack slots
+//! reported to MMTk as ordinary slots, so moving plans (GenCopy, SemiSpace, ...) can update them. Callee-saved registers
+//! are recovered from unwind info of the frames between the call-site and the safepoint, but the location they were
+//! spilled to is not known: objects referenced from registers are reported as pinning roots and are not moved by that GC.
+//! Registers are only supported on x86-64, other architectures accept [`StackMapLocation::Stack`] only.
+//!
+//! Stack maps are scanned only when [`Runtime::SCAN_JIT_FRAMES`] is set to true.
+
+use std::{collections::BTreeMap, fmt, ops::Bound};
+
+use macroassembler::assembler::{
+    abstract_macro_assembler::Call, link_buffer::LinkBuffer, TargetMacroAssembler,
+};
+use mmtk::{
+    util::{Address, VMMutatorThread},
+    vm::RootsWorkFactory,
+};
+use parking_lot::RwLock;
+
+use crate::Runtime;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use crate::{
+    mm::slot::SlotExt,
+    runtime::{
+        threads::Thread,
+        unwind::{self, framehop::AllocationPolicy, FrameAddress, UnwindIterator, UnwinderNative},
+    },
+    SlotOf, ThreadOf,
+};
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use mmtk::util::ObjectReference;
+
+/// Location of an object reference in a JIT frame.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub enum StackMapLocation {
+    /// Stack slot at the given offset from frame pointer.
+    Stack(i32),
+    /// Callee-saved register, DWARF register number. Object it references is pinned, see module docs.
+    Register(u16),
+}
+
+/// Stack map contains a location GC can't locate in a stopped thread.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct UnsupportedLocation(pub StackMapLocation);
+
+impl fmt::Display for UnsupportedLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unsupported stack map location {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnsupportedLocation {}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct StackMap {
+    pub locations: Vec<StackMapLocation>,
+}
+
+impl StackMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_stack_slot(mut self, offset: i32) -> Self {
+        self.locations.push(StackMapLocation::Stack(offset));
+        self
+    }
+
+    pub fn with_register(mut self, register: u16) -> Self {
+        self.locations.push(StackMapLocation::Register(register));
+        self
+    }
+
+    /// Check that every location of the map can be found by GC: stack slots and callee-saved registers
+    /// other than frame pointer.
+    pub fn validate(&self) -> Result<(), UnsupportedLocation> {
+        match self
+            .locations
+            .iter()
+            .find(|location| !is_supported_location(**location))
+        {
+            Some(&location) => Err(UnsupportedLocation(location)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Registry of all stack maps in the process.
+pub struct StackMaps {
+    maps: RwLock<BTreeMap<Address, StackMap>>,
+}
+
+impl StackMaps {
+    pub const fn new() -> Self {
+        Self {
+            maps: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Register `map` for the call-site with `return_address`. Fails if map has locations GC can't find, see [`StackMap::validate`].
+    pub fn register(
+        &self,
+        return_address: Address,
+        map: StackMap,
+    ) -> Result<(), UnsupportedLocation> {
+        map.validate()?;
+        self.maps.write().insert(return_address, map);
+        Ok(())
+    }
+
+    /// Remove all stack maps in the range `[start, end)`. Must be invoked when JIT code is freed.
+    pub fn unregister_range(&self, start: Address, end: Address) {
+        let mut maps = self.maps.write();
+        let keys = maps
+            .range((Bound::Included(start), Bound::Excluded(end)))
+            .map(|(&key, _)| key)
+            .collect::<Vec<_>>();
+
+        for key in keys {
+            maps.remove(&key);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.maps.read().is_empty()
+    }
+
+    pub fn lookup<T>(&self, return_address: Address, f: impl FnOnce(&StackMap) -> T) -> Option<T> {
+        self.maps.read().get(&return_address).map(f)
+    }
+
+    /// Record `map` for `call`. Stack map is registered once `masm` is linked and [`LinkBuffer`] is finalized.
+    /// Map is validated right away, see [`StackMap::validate`].
+    pub fn record(
+        &'static self,
+        masm: &mut TargetMacroAssembler,
+        call: Call,
+        map: StackMap,
+    ) -> Result<(), UnsupportedLocation> {
+        map.validate()?;
+        masm.add_link_task(Box::new(move |buffer: &mut LinkBuffer| {
+            let return_address = Address::from_ptr(buffer.get_rx_linker_address(call.label));
+            self.register(return_address, map)
+                .expect("stack map was validated when recorded");
+        }));
+        Ok(())
+    }
+}
+
+/// Global stack map registry.
+pub static STACK_MAPS: StackMaps = StackMaps::new();
+
+fn is_supported_location(location: StackMapLocation) -> bool {
+    match location {
+        StackMapLocation::Stack(_) => true,
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        StackMapLocation::Register(register) => unwind::is_callee_saved_register(register),
+        #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+        StackMapLocation::Register(_) => false,
+    }
+}
+
+/// Walk `frames` and report all the locations described by stack maps to `factory`. Stack slots are reported
+/// as slots, objects held in registers as pinning roots.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub fn scan_frames<R: Runtime, P: AllocationPolicy>(
+    frames: &mut UnwindIterator<'_, '_, UnwinderNative<&[u8], P>>,
+    factory: &mut impl RootsWorkFactory<R::Slot>,
+) {
+    let mut slots = Vec::new();
+    let mut pinned = Vec::new();
+
+    // stops at the outermost frame or once a frame can't be unwound
+    while let Ok(Some(frame)) = frames.next() {
+        // first frame is the one that saved stack state, not a call-site
+        let FrameAddress::ReturnAddress(return_address) = frame else {
+            continue;
+        };
+        let return_address = unsafe { Address::from_usize(return_address.get() as usize) };
+
+        STACK_MAPS.lookup(return_address, |map| {
+            // iterator is unwound to the caller: registers describe the JIT frame at the call-site
+            let regs = frames.regs();
+            let fp = unwind::frame_pointer(regs);
+
+            for &location in map.locations.iter() {
+                match location {
+                    StackMapLocation::Stack(offset) => {
+                        slots.push(SlotOf::<R>::from_pointer(
+                            fp.offset(offset as isize).to_mut_ptr::<ObjectReference>(),
+                        ));
+                    }
+
+                    StackMapLocation::Register(register) => {
+                        // validated by `StackMaps::register`
+                        let value = unwind::register_value(regs, register).unwrap();
+                        if let Some(object) =
+                            ObjectReference::from_raw_address(unsafe { Address::from_usize(value) })
+                        {
+                            pinned.push(object);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    if !slots.is_empty() {
+        factory.create_process_roots_work(slots);
+    }
+
+    if !pinned.is_empty() {
+        factory.create_process_pinning_roots_work(pinned);
+    }
+}
+
+/// Scan JIT frames of `thread`, which must be blocked for GC or parked.
+pub fn scan_thread_frames<R: Runtime>(
+    thread: VMMutatorThread,
+    factory: &mut impl RootsWorkFactory<R::Slot>,
+) {
+    if STACK_MAPS.is_empty() {
+        return;
+    }
+
+    cfg_if::cfg_if! {
+        if #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))] {
+            let tls = ThreadOf::<R>::tls(thread.0);
+            let mut cache = unwind::CacheNative::new();
+
+            // thread stays blocked while its roots are scanned
+            if let Some(mut frames) = unsafe { unwind::process_unwinder().iter_frames_of(tls, &mut cache) } {
+                scan_frames::<R, _>(&mut frames, factory);
+            }
+        } else {
+            // rejected by `VMKitBuilder::build`
+            let _ = (thread, factory);
+            unreachable!("JIT frames can't be unwound on this architecture");
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! # GC statistics and events
+//!
+//! VMKit accounts every collection: pause time, number of stopped mutators, bytes allocated since previous
+//! GC and bytes freed by it. Accumulated [`GcStats`] can be queried at any time with [`VMKit::gc_stats`],
+//! e.g to back `gc.stats()` exposed to user code.
+//!
+//! Runtime can observe collections with [`Runtime::gc_event`]. Events are delivered on GC threads:
+//! [`GcEvent::Start`] and [`GcEvent::End`] while mutators are stopped, phases from GC workers.
+//! Handlers must not allocate or block on mutators.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+use crate::{Runtime, VMKit};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GcPhase {
+    /// All mutators are stopped, root scanning begins.
+    MutatorsStopped,
+    /// Transitive closure is done, weak references are processed.
+    WeakReferences,
+    /// Finalizable objects were found and finalization was scheduled.
+    Finalization,
+    /// Objects were moved and references were updated.
+    PostForwarding,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum GcEvent<'a> {
+    /// GC was triggered, mutators are about to be stopped.
+    Start {
+        collection: usize,
+    },
+    Phase(GcPhase),
+    /// GC is finished, mutators are about to be resumed.
+    End(&'a CollectionStats),
+}
+
+/// Statistics of a single collection.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct CollectionStats {
+    /// Index of the collection, starting from 1.
+    pub collection: usize,
+    /// Time between stopping and resuming mutators.
+    pub pause: Duration,
+    pub mutators_stopped: usize,
+    /// Bytes allocated between previous collection and this one.
+    pub bytes_allocated: usize,
+    pub bytes_freed: usize,
+    /// Heap usage after collection.
+    pub bytes_used: usize,
+}
+
+/// Statistics accumulated over all collections.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct GcStats {
+    pub collections: usize,
+    pub total_pause: Duration,
+    pub max_pause: Duration,
+    pub total_bytes_allocated: usize,
+    pub total_bytes_freed: usize,
+    /// Statistics of the last collection, `None` if GC never happened.
+    pub last: Option<CollectionStats>,
+}
+
+struct InFlight {
+    start: Instant,
+    used_before: usize,
+    mutators_stopped: usize,
+}
+
+#[derive(Default)]
+pub(crate) struct GcStatsCollector {
+    stats: Mutex<GcStats>,
+    in_flight: Mutex<Option<InFlight>>,
+    /// Used bytes after previous collection, to compute bytes allocated between collections.
+    used_after_last: Mutex<usize>,
+    weak_phase_reported: AtomicBool,
+}
+
+impl GcStatsCollector {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Invoked from `stop_all_mutators` before mutators are stopped.
+    pub(crate) fn gc_start<R: Runtime>(&self) {
+        let used_before = mmtk::memory_manager::used_bytes(&R::vmkit().mmtk);
+        *self.in_flight.lock() = Some(InFlight {
+            start: Instant::now(),
+            used_before,
+            mutators_stopped: 0,
+        });
+        self.weak_phase_reported.store(false, Ordering::Relaxed);
+
+        let collection = self.stats.lock().collections + 1;
+        R::gc_event(GcEvent::Start { collection });
+    }
+
+    /// Invoked once all mutators are stopped.
+    pub(crate) fn mutators_stopped<R: Runtime>(&self, count: usize) {
+        if let Some(in_flight) = self.in_flight.lock().as_mut() {
+            in_flight.mutators_stopped = count;
+        }
+
+        R::gc_event(GcEvent::Phase(GcPhase::MutatorsStopped));
+    }
+
+    pub(crate) fn phase<R: Runtime>(&self, phase: GcPhase) {
+        // weak reference processing is repeated until fixpoint, report it once
+        if phase == GcPhase::WeakReferences
+            && self.weak_phase_reported.swap(true, Ordering::Relaxed)
+        {
+            return;
+        }
+
+        R::gc_event(GcEvent::Phase(phase));
+    }
+
+    /// Invoked from `resume_mutators` before mutators are resumed.
+    pub(crate) fn gc_end<R: Runtime>(&self) {
+        let Some(in_flight) = self.in_flight.lock().take() else {
+            return;
+        };
+
+        let used_after = mmtk::memory_manager::used_bytes(&R::vmkit().mmtk);
+        let bytes_allocated = {
+            let mut used_after_last = self.used_after_last.lock();
+            let allocated = in_flight.used_before.saturating_sub(*used_after_last);
+            *used_after_last = used_after;
+            allocated
+        };
+
+        let collection = {
+            let mut stats = self.stats.lock();
+            let collection = CollectionStats {
+                collection: stats.collections + 1,
+                pause: in_flight.start.elapsed(),
+                mutators_stopped: in_flight.mutators_stopped,
+                bytes_allocated,
+                bytes_freed: in_flight.used_before.saturating_sub(used_after),
+                bytes_used: used_after,
+            };
+
+            stats.collections += 1;
+            stats.total_pause += collection.pause;
+            stats.max_pause = stats.max_pause.max(collection.pause);
+            stats.total_bytes_allocated += collection.bytes_allocated;
+            stats.total_bytes_freed += collection.bytes_freed;
+            stats.last = Some(collection);
+            collection
+        };
+
+        R::gc_event(GcEvent::End(&collection));
+    }
+}
+
+impl<R: Runtime> VMKit<R> {
+    /// Snapshot of GC statistics accumulated so far.
+    pub fn gc_stats(&self) -> GcStats {
+        *self.gc_stats.stats.lock()
+    }
+}
@@ -0,0 +1,78 @@
+//! # GC stress mode
+//!
+//! Debug mode that forces collections much more often than heap pressure would, so that missing roots
+//! and unrooted temporaries are found quickly instead of crashing once in a while:
+//!
+//! - `gc_stress=N`: force GC on every N-th allocation of a thread in `vmkit_allocate*`.
+//! - `gc_stress_yieldpoints`: force GC at every yieldpoint. Yieldpoints of every thread are kept armed so each
+//!   [`check_yieldpoint`](crate::runtime::threads::Thread::check_yieldpoint) takes the yieldpoint.
+//!
+//! Collections are requested as user GCs, so `ignore_system_gc` must be off. Combine with `verify_heap` to check heap consistency after each collection, see [`verifier`](super::verifier).
+
+use std::{cell::Cell, sync::atomic::Ordering};
+
+use mmtk::util::VMMutatorThread;
+
+use crate::{
+    runtime::{
+        options::{mmtkflags_gc_stress, mmtkflags_gc_stress_yieldpoints},
+        DisableGCScope,
+    },
+    Runtime, ThreadOf,
+};
+
+thread_local! {
+    /// Set while stress GC is requested by current thread, GC itself goes through yieldpoints.
+    static IN_STRESS_GC: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Is any stress mode enabled?
+#[inline(always)]
+pub fn is_enabled() -> bool {
+    *mmtkflags_gc_stress() != 0 || *mmtkflags_gc_stress_yieldpoints()
+}
+
+/// Invoked before every allocation, object is not allocated yet so GC can't observe it half-initialized.
+#[inline(always)]
+pub(crate) fn on_allocation<R: Runtime>(thread: VMMutatorThread) {
+    let interval = *mmtkflags_gc_stress();
+    if interval == 0 {
+        return;
+    }
+
+    let allocations = &ThreadOf::<R>::tls(thread.0).stress_allocations;
+    if allocations.fetch_add(1, Ordering::Relaxed) % interval == interval - 1 {
+        force_gc::<R>(thread);
+    }
+}
+
+/// Invoked once thread finished yieldpoint.
+#[inline(always)]
+pub(crate) fn on_yieldpoint<R: Runtime>(thread: VMMutatorThread) {
+    if *mmtkflags_gc_stress_yieldpoints() {
+        force_gc::<R>(thread);
+        arm_yieldpoint::<R>(thread);
+    }
+}
+
+/// Make the next yieldpoint check of `thread` take the yieldpoint when `gc_stress_yieldpoints` is set.
+/// Invoked when thread starts and after every taken yieldpoint.
+#[inline(always)]
+pub(crate) fn arm_yieldpoint<R: Runtime>(thread: VMMutatorThread) {
+    if *mmtkflags_gc_stress_yieldpoints() {
+        ThreadOf::<R>::tls(thread.0)
+            .take_yieldpoint
+            .store(1, Ordering::Relaxed);
+    }
+}
+
+#[cold]
+fn force_gc<R: Runtime>(thread: VMMutatorThread) {
+    if IN_STRESS_GC.replace(true) {
+        return;
+    }
+
+    DisableGCScope::<R>::request_gc(thread);
+
+    IN_STRESS_GC.set(false);
+}
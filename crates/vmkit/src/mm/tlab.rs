@@ -10,26 +10,36 @@ use mmtk::{
         alloc::{AllocatorSelector, BumpAllocator, BumpPointer, ImmixAllocator},
         Address,
     },
-    Mutator,
+    AllocationSemantics, Mutator,
 };
 
-use crate::{MMTKVMKit, Runtime};
+use crate::{mm::sampling, objectmodel::vtable::VTablePointer, MMTKVMKit, Runtime};
 
 #[repr(C)]
 pub struct TLAB<R: Runtime> {
     bump: BumpPointer,
     selector: AllocatorSelector,
     los_threshold: usize,
+    /// Size of the current buffer when it was refilled, used to compute how much was bump-allocated from it.
+    refill_size: usize,
+    /// Bytes allocated since the last allocation sample.
+    bytes_since_sample: usize,
     marker: PhantomData<R>,
 }
 
 impl<R: Runtime> TLAB<R> {
     pub const LOS_THRESHOLD_OFFSET: usize = offset_of!(Self, los_threshold);
+    /// Offset of the bump cursor, JIT code loads and stores it directly.
+    pub const CURSOR_OFFSET: usize = offset_of!(Self, bump) + offset_of!(BumpPointer, cursor);
+    /// Offset of the bump limit, allocation takes the slow path once cursor reaches it.
+    pub const LIMIT_OFFSET: usize = offset_of!(Self, bump) + offset_of!(BumpPointer, limit);
+    /// Alignment of every object allocated out of TLAB.
+    pub const ALIGNMENT: usize = align_of::<usize>() * 2;
 
     pub fn new() -> Self {
         let selector = mmtk::memory_manager::get_allocator_mapping(
             &R::vmkit().mmtk,
-            mmtk::AllocationSemantics::Default,
+            AllocationSemantics::Default,
         );
 
         let los_threshold = R::vmkit()
@@ -44,25 +54,30 @@ impl<R: Runtime> TLAB<R> {
             },
             los_threshold,
             selector,
+            refill_size: 0,
+            bytes_since_sample: 0,
             marker: PhantomData,
         }
     }
 
+    /// Allocate `size` bytes. Returns the address and semantics of the space it was allocated in: objects
+    /// at or above LOS threshold go to large object space, caller must run `post_alloc` for them.
     pub fn allocate(
         &mut self,
         mutator: &mut Mutator<MMTKVMKit<R>>,
         size: usize,
         align: usize,
-    ) -> Address {
+        vtable: VTablePointer,
+    ) -> (Address, AllocationSemantics) {
         let result = self.bump.cursor.align_up(align);
 
-        if result + size >= self.bump.limit {
-            return self.allocate_slow(mutator, size, align);
+        if size >= self.los_threshold || result + size >= self.bump.limit {
+            return self.allocate_slow(mutator, size, align, vtable);
         }
 
         self.bump.cursor = result + size;
 
-        result
+        (result, AllocationSemantics::Default)
     }
 
     pub fn allocate_slow(
@@ -70,26 +85,36 @@ impl<R: Runtime> TLAB<R> {
         mutator: &mut Mutator<MMTKVMKit<R>>,
         size: usize,
         align: usize,
-    ) -> Address {
+        vtable: VTablePointer,
+    ) -> (Address, AllocationSemantics) {
+        let interval = sampling::sampling_interval();
+        if interval != 0 {
+            let remaining = self.bump.limit.as_usize() - self.bump.cursor.as_usize();
+            self.bytes_since_sample += self.refill_size.saturating_sub(remaining) + size;
+
+            if self.bytes_since_sample >= interval {
+                sampling::sample::<R>(vtable, std::mem::take(&mut self.bytes_since_sample));
+            }
+        }
+
         unsafe {
             self.flush_cursors(mutator);
         }
-        let addr = if size >= self.los_threshold {
-            mmtk::memory_manager::alloc(mutator, size, align, 0, mmtk::AllocationSemantics::Los)
+        let semantics = if size >= self.los_threshold {
+            AllocationSemantics::Los
+        } else {
+            AllocationSemantics::Default
+        };
+        let addr = if semantics == AllocationSemantics::Los {
+            mmtk::memory_manager::alloc(mutator, size, align, 0, semantics)
         } else {
-            mmtk::memory_manager::alloc_slow(
-                mutator,
-                size,
-                align,
-                0,
-                mmtk::AllocationSemantics::Default,
-            )
+            mmtk::memory_manager::alloc_slow(mutator, size, align, 0, semantics)
         };
 
         unsafe {
             self.bump_cursors(mutator);
         }
-        addr
+        (addr, semantics)
     }
 
     pub unsafe fn flush_cursors(&mut self, mutator: &mut Mutator<MMTKVMKit<R>>) {
@@ -120,6 +145,7 @@ impl<R: Runtime> TLAB<R> {
 
         // we bump downwards so start is bump_end and end is bump_cursor
         *bump_pointer = std::mem::take(&mut self.bump);
+        self.refill_size = 0;
     }
 
     pub unsafe fn bump_cursors(&mut self, mutator: &mut Mutator<MMTKVMKit<R>>) {
@@ -146,5 +172,6 @@ impl<R: Runtime> TLAB<R> {
         };
 
         self.bump = bump_pointer.clone();
+        self.refill_size = self.bump.limit.as_usize() - self.bump.cursor.as_usize();
     }
 }
@@ -0,0 +1,166 @@
+//! # Heap verifier
+//!
+//! Checks heap consistency after GC when `verify_heap` flag is set. Runs on GC worker once collection is done
+//! and before mutators are resumed. For every object in the heap:
+//!
+//! - Vtable must be a valid [`GCVTable`], i.e start with [`GCVTable::MAGIC`].
+//! - Every traced slot must be null or point to a valid object, validity is determined by VO bits.
+//! - No slot points to a forwarded object: all references to moved objects must be updated by GC.
+//!
+//! Failures are almost always caused by missing roots or by objects which are traced before being fully initialized.
+//! Requires VO bits, see [`Runtime::VO_BIT`].
+
+use std::fmt;
+
+use mmtk::{
+    util::{object_forwarding, ObjectReference},
+    vm::slot::Slot,
+};
+
+use crate::{
+    mm::scanning::{Tracer, Visitor},
+    objectmodel::{
+        header::HeapObjectHeader,
+        vtable::{GCVTable, TraceCallback, VTable, VTablePointer},
+    },
+    MMTKVMKit, Runtime, VMKit, VTableOf,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VerificationError {
+    /// Object header does not point to a valid vtable.
+    InvalidVTable {
+        object: ObjectReference,
+        vtable: VTablePointer,
+    },
+    /// Object has a reference to something that is not an object.
+    DanglingReference {
+        object: ObjectReference,
+        target: ObjectReference,
+    },
+    /// Object has a reference to an object that was moved, reference was not updated by GC.
+    ForwardedReference {
+        object: ObjectReference,
+        target: ObjectReference,
+    },
+}
+
+impl fmt::Display for VerificationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidVTable { object, vtable } => write!(
+                f,
+                "object {} has invalid vtable {}",
+                object,
+                vtable.0.to_address()
+            ),
+            Self::DanglingReference { object, target } => {
+                write!(f, "object {} references non-object {}", object, target)
+            }
+            Self::ForwardedReference { object, target } => {
+                write!(
+                    f,
+                    "object {} references forwarded object {}",
+                    object, target
+                )
+            }
+        }
+    }
+}
+
+impl<R: Runtime> VMKit<R> {
+    /// Verify the heap. World must be stopped, e.g from [`stop_the_world`](crate::runtime::threads::stop_the_world).
+    ///
+    /// Only objects whose vtable is valid are traced.
+    pub fn verify_heap(&self) -> Vec<VerificationError> {
+        assert!(
+            R::VO_BIT && cfg!(feature = "vo-bit"),
+            "heap verification requires VO bits"
+        );
+
+        let mut errors = Vec::new();
+
+        #[cfg(feature = "vo-bit")]
+        mmtk::memory_manager::enumerate_objects(&self.mmtk, |object| {
+            verify_object::<R>(object, &mut errors);
+        });
+
+        errors
+    }
+}
+
+fn verify_object<R: Runtime>(object: ObjectReference, errors: &mut Vec<VerificationError>) {
+    let vtable = <&HeapObjectHeader<R>>::from(object).vtable();
+    let address = vtable.0.to_address();
+
+    // check the pointer before dereferencing it, a corrupt vtable is the most common failure
+    if address.is_zero()
+        || !address.is_aligned_to(align_of::<VTableOf<R>>())
+        || VTableOf::<R>::from_pointer(vtable).gc().magic != GCVTable::<R>::MAGIC
+    {
+        errors.push(VerificationError::InvalidVTable { object, vtable });
+        return;
+    }
+
+    let mut check = |target: ObjectReference| {
+        if let Some(error) = verify_reference::<R>(object, target) {
+            errors.push(error);
+        }
+    };
+
+    match VTableOf::<R>::from_pointer(vtable).gc().trace {
+        TraceCallback::ScanSlots(scan) => {
+            let mut sv = |slot: R::Slot| {
+                if let Some(target) = slot.load() {
+                    check(target);
+                }
+            };
+            let mut visitor = Visitor::<R>::for_heap_walk(&mut sv, object);
+            scan(object, &mut visitor);
+        }
+
+        TraceCallback::ScanObjects(scan) => {
+            let mut sv = |target| {
+                check(target);
+                target
+            };
+            let mut tracer = Tracer::<R>::for_heap_walk(&mut sv, object);
+            scan(object, &mut tracer);
+        }
+
+        TraceCallback::NoTrace => (),
+    }
+}
+
+fn verify_reference<R: Runtime>(
+    object: ObjectReference,
+    target: ObjectReference,
+) -> Option<VerificationError> {
+    if mmtk::memory_manager::is_in_mmtk_spaces(target)
+        && object_forwarding::is_forwarded::<MMTKVMKit<R>>(target)
+    {
+        return Some(VerificationError::ForwardedReference { object, target });
+    }
+
+    #[cfg(feature = "vo-bit")]
+    if mmtk::memory_manager::is_mmtk_object(target.to_raw_address()).is_some() {
+        return None;
+    }
+
+    Some(VerificationError::DanglingReference { object, target })
+}
+
+/// Invoked by GC once collection is finished and before mutators are resumed.
+pub(crate) fn verify_after_gc<R: Runtime>() {
+    let errors = R::vmkit().verify_heap();
+
+    if errors.is_empty() {
+        return;
+    }
+
+    for error in errors.iter() {
+        log::error!("heap verification: {}", error);
+    }
+
+    panic!("heap verification failed with {} errors", errors.len());
+}
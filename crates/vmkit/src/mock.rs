@@ -9,7 +9,7 @@ use std::{
 };
 
 use mmtk::{
-    util::{Address, OpaquePointer, VMMutatorThread, VMThread},
+    util::{Address, ObjectReference, OpaquePointer, VMMutatorThread, VMThread},
     vm::{slot::SimpleSlot, RootsWorkFactory},
 };
 
@@ -27,6 +27,9 @@ impl Runtime for MockVM {
     type VTable = GCVTable<Self>;
     type Thread = MockThread;
 
+    const MARK_WORD: bool = true;
+    const SUPPORTS_REFERENCES: bool = true;
+
     fn out_of_memory(_thread: VMThread, _error: mmtk::util::alloc::AllocationError) {}
 
     fn scan_roots(_roots: impl mmtk::vm::RootsWorkFactory<Self::Slot>) {}
@@ -37,6 +40,17 @@ impl Runtime for MockVM {
 
     fn post_forwarding() {}
 
+    /// Reference objects of MockVM hold referent in the first word of their body.
+    fn referent_slot(reference: ObjectReference) -> Self::Slot {
+        SimpleSlot::from_address(reference.to_raw_address())
+    }
+
+    fn clear_referent(reference: ObjectReference) {
+        unsafe {
+            reference.to_raw_address().store(Address::ZERO);
+        }
+    }
+
     fn stack_overflow(_ip: Address, _addr: Address) -> ! {
         loop {}
     }
@@ -8,7 +8,8 @@ use std::marker::PhantomData;
 
 use crate::mm::slot::SlotExt;
 use crate::{MMTKVMKit, Runtime, VTableOf};
-use constants::{OBJECT_HASH_OFFSET, OBJECT_HASH_SIZE, OBJECT_REF_OFFSET};
+use constants::{MARK_WORD_SIZE, OBJECT_HASH_OFFSET, OBJECT_HASH_SIZE, OBJECT_REF_OFFSET};
+use mark_word::MarkWord;
 
 use header::{HashState, HeapObjectHeader};
 use mmtk::{
@@ -46,6 +47,11 @@ enum MoveTarget {
 }
 
 impl<R: Runtime> ObjectModel<R> {
+    /// Size of the mark word in front of the header, zero unless [`Runtime::MARK_WORD`] is enabled.
+    pub const MARK_WORD_SIZE: usize = if R::MARK_WORD { MARK_WORD_SIZE } else { 0 };
+    /// Offset of the identity hash slot from object reference, valid for [`HashState::HashedAndMoved`] objects.
+    pub const HASH_OFFSET: isize = OBJECT_HASH_OFFSET - Self::MARK_WORD_SIZE as isize;
+
     pub(crate) fn get_alignment(object: ObjectReference) -> usize {
         let header = <&HeapObjectHeader<R>>::from(object);
 
@@ -61,6 +67,7 @@ impl<R: Runtime> ObjectModel<R> {
         let hash_state = header.hash_state();
 
         size_of::<HeapObjectHeader<R>>()
+            + Self::MARK_WORD_SIZE
             + (hash_state != HashState::Unhashed)
                 .then_some(OBJECT_HASH_SIZE)
                 .unwrap_or(0)
@@ -77,6 +84,8 @@ impl<R: Runtime> ObjectModel<R> {
             size += compute_size(object).get();
         }
 
+        size += Self::MARK_WORD_SIZE;
+
         if header.hash_state() == HashState::HashedAndMoved {
             size += OBJECT_HASH_SIZE;
         }
@@ -95,6 +104,8 @@ impl<R: Runtime> ObjectModel<R> {
             size += compute_size(object).get();
         }
 
+        size += Self::MARK_WORD_SIZE;
+
         if header.hash_state() != HashState::Unhashed {
             size += OBJECT_HASH_SIZE;
         }
@@ -102,55 +113,55 @@ impl<R: Runtime> ObjectModel<R> {
         raw_align_up(size, size_of::<usize>())
     }
 
-    fn move_object(
-        from_obj: ObjectReference,
-        mut to: MoveTarget,
-        num_bytes: usize,
-    ) -> ObjectReference {
-        let copy_bytes = num_bytes;
-
-        let obj_ref_offset = OBJECT_REF_OFFSET as isize;
+    fn move_object(from_obj: ObjectReference, to: MoveTarget, num_bytes: usize) -> ObjectReference {
+        let hash_state = <&HeapObjectHeader<R>>::from(from_obj).hash_state();
 
-        /*if hash_state == HashState::Hashed {
-            copy_bytes -= OBJECT_HASH_SIZE;
-
-            if let MoveTarget::ToAddress(ref mut addr) = to {
-                *addr += OBJECT_HASH_SIZE;
-            }
-        } else if hash_state == HashState::HashedAndMoved {
-            obj_ref_offset += OBJECT_HASH_SIZE as isize;
-        }*/
+        // hashed objects get a hash word in front of the header once moved
+        let obj_ref_offset = if hash_state == HashState::Unhashed {
+            OBJECT_REF_OFFSET + Self::MARK_WORD_SIZE
+        } else {
+            OBJECT_REF_OFFSET + Self::MARK_WORD_SIZE + OBJECT_HASH_SIZE
+        };
 
         let (to_address, to_obj) = match to {
             MoveTarget::ToAddress(addr) => {
                 let obj =
                     unsafe { ObjectReference::from_raw_address_unchecked(addr + obj_ref_offset) };
-
-                debug_assert!(obj.to_raw_address() == addr + obj_ref_offset);
                 (addr, obj)
             }
 
-            MoveTarget::ToObject(obj) => {
-                let addr = obj.to_raw_address() + (-obj_ref_offset);
-                debug_assert!(obj.to_raw_address() == addr + obj_ref_offset);
-                (addr, obj)
-            }
+            MoveTarget::ToObject(obj) => (obj.to_raw_address() - obj_ref_offset, obj),
         };
 
-        let from_address = from_obj.to_raw_address() + (-obj_ref_offset);
-        if let MoveTarget::ToAddress(ref mut addr) = to {
-            *addr += OBJECT_HASH_SIZE;
-        }
-        println!(
-            "move_object: {}->{} ({} bytes)",
-            from_address, to_address, copy_bytes
-        );
+        let from_address = Self::object_start_ref(from_obj);
+
+        // regions may overlap when objects are slid by mark-compact
         unsafe {
-            std::ptr::copy_nonoverlapping(
-                from_address.to_ptr::<u8>(),
-                to_address.to_mut_ptr::<u8>(),
-                copy_bytes,
-            );
+            match hash_state {
+                HashState::Unhashed | HashState::HashedAndMoved => {
+                    std::ptr::copy(
+                        from_address.to_ptr::<u8>(),
+                        to_address.to_mut_ptr::<u8>(),
+                        num_bytes,
+                    );
+                }
+
+                HashState::Hashed => {
+                    // first move of a hashed object: old address becomes its identity hash
+                    let hash = from_obj.to_raw_address().as_usize() as u64;
+                    std::ptr::copy(
+                        from_address.to_ptr::<u8>(),
+                        (to_address + OBJECT_HASH_SIZE).to_mut_ptr::<u8>(),
+                        num_bytes - OBJECT_HASH_SIZE,
+                    );
+                    to_address.store(hash);
+                    <&HeapObjectHeader<R>>::from(to_obj).set_hash_state(HashState::HashedAndMoved);
+                }
+            }
+        }
+
+        if R::MARK_WORD {
+            MarkWord::from_object::<R>(to_obj).increment_age();
         }
 
         to_obj
@@ -178,11 +189,10 @@ impl<R: Runtime> ObjectModel<R> {
         let hash_state = header.hash_state();
 
         if hash_state == HashState::HashedAndMoved {
-            return object.to_raw_address()
-                + (-(OBJECT_REF_OFFSET as isize + OBJECT_HASH_SIZE as isize));
+            return object.to_raw_address() + Self::HASH_OFFSET;
         }
 
-        object.to_raw_address() + (-(OBJECT_REF_OFFSET as isize))
+        object.to_raw_address() + (-((OBJECT_REF_OFFSET + Self::MARK_WORD_SIZE) as isize))
     }
 }
 
@@ -196,7 +206,7 @@ const FORWARDING_BITS_METADATA_SPEC: VMLocalForwardingBitsSpec =
 const LOS_METADATA_SPEC: VMLocalLOSMarkNurserySpec = VMLocalLOSMarkNurserySpec::side_first();
 
 impl<R: Runtime> mmtk::vm::ObjectModel<MMTKVMKit<R>> for ObjectModel<R> {
-    const OBJECT_REF_OFFSET_LOWER_BOUND: isize = OBJECT_HASH_OFFSET;
+    const OBJECT_REF_OFFSET_LOWER_BOUND: isize = ObjectModel::<R>::HASH_OFFSET;
     const UNIFIED_OBJECT_REFERENCE_ADDRESS: bool = false;
     const LOCAL_MARK_BIT_SPEC: VMLocalMarkBitSpec = MARKING_METADATA_SPEC;
     const GLOBAL_LOG_BIT_SPEC: VMGlobalLogBitSpec = LOGGING_SIDE_METADATA_SPEC;
@@ -216,7 +226,7 @@ impl<R: Runtime> mmtk::vm::ObjectModel<MMTKVMKit<R>> for ObjectModel<R> {
     }
 
     fn get_align_offset_when_copied(object: ObjectReference) -> usize {
-        ObjectModel::<R>::get_alignment(object)
+        ObjectModel::<R>::get_offset_for_alignment(object)
     }
 
     fn get_align_when_copied(object: ObjectReference) -> usize {
@@ -232,7 +242,7 @@ impl<R: Runtime> mmtk::vm::ObjectModel<MMTKVMKit<R>> for ObjectModel<R> {
     }
 
     fn get_reference_when_copied_to(from: ObjectReference, to: Address) -> ObjectReference {
-        let mut res = to;
+        let mut res = to + ObjectModel::<R>::MARK_WORD_SIZE;
         let hash_state = <&HeapObjectHeader<R>>::from(from).hash_state();
         if hash_state != HashState::Unhashed {
             res += OBJECT_HASH_SIZE;
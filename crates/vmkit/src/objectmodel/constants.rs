@@ -2,3 +2,7 @@ pub const OBJECT_HEADER_OFFSET: isize = -(size_of::<usize>() as isize);
 pub const OBJECT_HASH_SIZE: usize = size_of::<u64>();
 pub const OBJECT_HASH_OFFSET: isize = OBJECT_HEADER_OFFSET + -(OBJECT_HASH_SIZE as isize);
 pub const OBJECT_REF_OFFSET: usize = (-OBJECT_HEADER_OFFSET) as usize;
+/// Size of the optional mark word, see [`mark_word`](super::mark_word).
+pub const MARK_WORD_SIZE: usize = size_of::<usize>();
+/// Offset of the mark word from object reference when [`Runtime::MARK_WORD`](crate::Runtime::MARK_WORD) is enabled.
+pub const OBJECT_MARK_WORD_OFFSET: isize = OBJECT_HEADER_OFFSET - MARK_WORD_SIZE as isize;
@@ -1,11 +1,34 @@
-/*use std::marker::PhantomData;
+//! # Ephemerons
+//!
+//! An ephemeron is a key-value pair where value is kept alive only as long as key is reachable from
+//! somewhere else. Ephemeron does not keep its key alive. Once key dies both key and value are cleared.
+//!
+//! Ephemerons are registered when the object that contains them is scanned and are processed in
+//! [`Scanning::process_weak_refs`](mmtk::vm::Scanning::process_weak_refs): value is traced only once key is known to be reachable, this is repeated until
+//! no new ephemerons are discovered, after that all ephemerons with dead keys are cleared.
+//!
+//! NOTE: Stores to ephemeron fields must go through write barrier as any other reference field, otherwise
+//! nursery GC won't discover ephemerons that live in mature objects. [`Ephemeron::set`] and [`Ephemeron::clear`]
+//! apply barriers on their own.
 
-use mmtk::util::ObjectReference;
+use std::marker::PhantomData;
 
-use crate::Runtime;
+use mmtk::{
+    util::{ObjectReference, VMMutatorThread},
+    vm::ObjectTracer,
+};
 
-use super::traits::TraceRefs;
+use crate::{
+    mm::{
+        scanning::{Tracer, Visitor},
+        vmkit_write_barrier_post, vmkit_write_barrier_pre,
+    },
+    Runtime,
+};
 
+use super::traits::{ScanSlots, TraceRefs};
+
+#[repr(C)]
 pub struct Ephemeron<R: Runtime> {
     pub(crate) key: Option<ObjectReference>,
     pub(crate) value: Option<ObjectReference>,
@@ -13,6 +36,14 @@ pub struct Ephemeron<R: Runtime> {
 }
 
 impl<R: Runtime> Ephemeron<R> {
+    pub const fn new(key: Option<ObjectReference>, value: Option<ObjectReference>) -> Self {
+        Self {
+            key,
+            value,
+            marker: PhantomData,
+        }
+    }
+
     pub fn key(&self) -> Option<ObjectReference> {
         self.key
     }
@@ -20,26 +51,88 @@ impl<R: Runtime> Ephemeron<R> {
     pub fn value(&self) -> Option<ObjectReference> {
         self.value
     }
+
+    /// Store `key` and `value` into ephemeron located inside of `owner`, write barriers are applied
+    /// for both fields.
+    pub fn set(
+        &mut self,
+        thread: VMMutatorThread,
+        owner: ObjectReference,
+        key: Option<ObjectReference>,
+        value: Option<ObjectReference>,
+    ) {
+        Self::store(thread, owner, &mut self.key, key);
+        Self::store(thread, owner, &mut self.value, value);
+    }
+
+    /// Clear ephemeron located inside of `owner`, see [`set`](Self::set).
+    pub fn clear(&mut self, thread: VMMutatorThread, owner: ObjectReference) {
+        self.set(thread, owner, None, None);
+    }
+
+    fn store(
+        thread: VMMutatorThread,
+        owner: ObjectReference,
+        field: &mut Option<ObjectReference>,
+        target: Option<ObjectReference>,
+    ) {
+        // `Option<ObjectReference>` has the same layout as `ObjectReference`, null is `None`.
+        let slot = field as *mut Option<ObjectReference> as *mut ObjectReference;
+        vmkit_write_barrier_pre::<R>(thread, owner, slot, target);
+        *field = target;
+        vmkit_write_barrier_post::<R>(thread, owner, slot, target);
+    }
+
+    /// Clear ephemeron whose key is dead. Invoked by GC, no barriers are required.
+    pub(crate) fn clear_dead(&mut self) {
+        self.key = None;
+        self.value = None;
+    }
+
+    /// Scan function for objects which *are* ephemerons i.e the body of object is [`Ephemeron<R>`].
+    /// Can be used as [`TraceCallback::ScanSlots`](super::vtable::TraceCallback::ScanSlots).
+    pub fn scan_object(object: ObjectReference, visitor: &mut Visitor<R>) {
+        let ephemeron = unsafe { object.to_raw_address().as_ref::<Self>() };
+        ephemeron.scan(visitor);
+    }
+
+    /// Trace function for objects which *are* ephemerons i.e the body of object is [`Ephemeron<R>`].
+    /// Can be used as [`TraceCallback::ScanObjects`](super::vtable::TraceCallback::ScanObjects).
+    pub fn trace_object(object: ObjectReference, tracer: &mut Tracer<R>) {
+        let ephemeron = unsafe { object.to_raw_address().as_mut_ref::<Self>() };
+        ephemeron.trace(tracer);
+    }
+
+    /// If key is reachable, update key and trace value. Returns false if key is not (yet) known to be
+    /// reachable.
+    pub(crate) fn trace_if_key_reachable(&mut self, tracer: &mut impl ObjectTracer) -> bool {
+        let Some(key) = self.key else {
+            self.value = None;
+            return true;
+        };
+
+        if !key.is_reachable() {
+            return false;
+        }
+
+        self.key = Some(key.get_forwarded_object().unwrap_or(key));
+
+        if let Some(value) = self.value {
+            self.value = Some(tracer.trace_object(value));
+        }
+
+        true
+    }
+}
+
+impl<R: Runtime> ScanSlots<R> for Ephemeron<R> {
+    fn scan(&self, visitor: &mut Visitor<R>) {
+        visitor.register_ephemeron(self);
+    }
 }
 
 impl<R: Runtime> TraceRefs<R> for Ephemeron<R> {
-    fn trace(&mut self, tracer: &mut crate::mm::scanning::Tracer<R>) {
-        tracer.register_weak_callback(
-            self,
-            Box::new(|addr, tracer| {
-                let ephemeron = unsafe { addr.cast::<Self>().as_mut().unwrap() };
-
-                if let Some(key) = ephemeron.key.filter(|key| key.is_reachable()) {
-                    ephemeron.key = Some(tracer.trace_object_reference(key));
-                    ephemeron.value = Some(
-                        tracer.trace_object_reference(ephemeron.value.expect("cannot be none")),
-                    );
-                } else {
-                    ephemeron.key = None;
-                    ephemeron.value = None;
-                }
-            }),
-        );
+    fn trace(&mut self, tracer: &mut Tracer<R>) {
+        tracer.register_ephemeron(self);
     }
 }
-*/
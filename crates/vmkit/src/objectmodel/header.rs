@@ -20,7 +20,7 @@ use num_traits::{FromPrimitive, ToPrimitive};
 
 use crate::{MMTKVMKit, Runtime};
 
-use super::{constants::OBJECT_HASH_SIZE, vtable::VTablePointer};
+use super::{vtable::VTablePointer, ObjectModel};
 
 impl<S: FromPrimitive> ToBitfield<S> for HashState {
     fn one() -> Self {
@@ -41,9 +41,9 @@ impl<S: ToPrimitive> FromBitfield<S> for HashState {
         let value = value.to_u8().unwrap();
 
         match value {
-            0 => Self::Hashed,
-            1 => Self::HashedAndMoved,
-            2 => Self::Unhashed,
+            0 => Self::Unhashed,
+            1 => Self::Hashed,
+            2 => Self::HashedAndMoved,
             _ => {
                 #[cfg(debug_assertions)]
                 {
@@ -98,10 +98,11 @@ impl<R: Runtime> HeapObjectHeader<R> {
         self.storage.update_synchronized::<HashStateBitfield>(state);
     }
 
+    /// Identity hash of the object: its address until the object is moved for the first time. Moving
+    /// GC stores the old address in a hash word in front of the header, see [`HashState`].
     pub fn hashcode(&self) -> u64 {
         let addr = Address::from_ref(self) + size_of::<Self>();
-        let objref = unsafe { ObjectReference::from_raw_address_unchecked(addr) };
-        let hashcode = objref.to_raw_address().as_usize() as u64;
+        let hashcode = addr.as_usize() as u64;
 
         match self.hash_state() {
             HashState::Hashed => hashcode,
@@ -111,7 +112,7 @@ impl<R: Runtime> HeapObjectHeader<R> {
             }
 
             HashState::HashedAndMoved => {
-                let hash_addr = addr - OBJECT_HASH_SIZE;
+                let hash_addr = addr + ObjectModel::<R>::HASH_OFFSET;
                 unsafe { hash_addr.load() }
             }
         }
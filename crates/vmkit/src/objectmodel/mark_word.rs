@@ -1 +1,114 @@
+//! # Mark word
+//!
+//! Optional second header word, enabled by [`Runtime::MARK_WORD`]. Mark word is placed right in front of
+//! [`HeapObjectHeader`](super::header::HeapObjectHeader) (and after identity hash slot of moved objects) so that object
+//! references and field offsets of the VM stay the same:
+//!
+//! ```text
+//! [hash (HashedAndMoved only)] [mark word] [header: vtable | hash state | forwarding bits] <- object reference
+//! ```
+//!
+//! Layout of the mark word:
+//!
+//! ```text
+//! 63             32 31          6 5    2 1    0
+//! | monitor index  |  spare bits  | age  | lock |
+//! ```
+//!
+//! - lock bits and monitor index belong to [`synchronizer`](crate::sync::synchronizer) when the runtime returns
+//!   the mark word as its [`Runtime::lock_word`];
+//! - age is the number of times the object was moved by GC, saturates at [`MAX_AGE`];
+//! - spare bits are left for the VM.
+//!
+//! Forwarding bits and forwarding pointer used by MMTk live in the header word, GC never overwrites the mark word
+//! and copies it together with the object, so lock state survives moves.
+//!
+//! Sizes in [`GCVTable`](super::vtable::GCVTable) and sizes passed to allocation functions do not include
+//! the mark word, it is accounted for by VMKit.
 
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use mmtk::util::ObjectReference;
+
+use crate::{
+    sync::synchronizer::{LockState, LOCK_BITS, MONITOR_MASK},
+    Runtime,
+};
+
+use super::constants::OBJECT_MARK_WORD_OFFSET;
+
+pub const AGE_SHIFT: usize = 2;
+pub const AGE_BITS: usize = 4;
+pub const MAX_AGE: u8 = (1 << AGE_BITS) - 1;
+const AGE_MASK: usize = (MAX_AGE as usize) << AGE_SHIFT;
+
+pub const SPARE_SHIFT: usize = AGE_SHIFT + AGE_BITS;
+pub const SPARE_BITS: usize = 26;
+const SPARE_MASK: usize = ((1 << SPARE_BITS) - 1) << SPARE_SHIFT;
+
+const _: () = {
+    assert!(LOCK_BITS & AGE_MASK == 0);
+    assert!((LOCK_BITS | AGE_MASK) & SPARE_MASK == 0);
+    assert!((LOCK_BITS | AGE_MASK | SPARE_MASK) & MONITOR_MASK == 0);
+};
+
+#[repr(transparent)]
+pub struct MarkWord {
+    word: AtomicUsize,
+}
+
+impl MarkWord {
+    /// Mark word of a freshly allocated object: unlocked, age zero, spare bits cleared.
+    pub const fn new() -> Self {
+        Self {
+            word: AtomicUsize::new(0),
+        }
+    }
+
+    /// Mark word of `object`. [`Runtime::MARK_WORD`] must be enabled.
+    pub fn from_object<'a, R: Runtime>(object: ObjectReference) -> &'a Self {
+        debug_assert!(R::MARK_WORD, "runtime does not have mark words");
+        unsafe { (object.to_raw_address() + OBJECT_MARK_WORD_OFFSET).as_ref() }
+    }
+
+    /// Raw mark word, for code that needs to CAS the whole word (e.g JIT fast paths).
+    pub fn raw(&self) -> &AtomicUsize {
+        &self.word
+    }
+
+    pub fn load(&self) -> usize {
+        self.word.load(Ordering::Acquire)
+    }
+
+    pub fn lock_state(&self) -> LockState {
+        LockState::decode(self.load())
+    }
+
+    pub fn age(&self) -> u8 {
+        ((self.load() & AGE_MASK) >> AGE_SHIFT) as u8
+    }
+
+    /// Increment age of the object unless it's already [`MAX_AGE`]. Invoked by GC when object is moved.
+    pub fn increment_age(&self) {
+        let _ = self
+            .word
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |word| {
+                let age = (word & AGE_MASK) >> AGE_SHIFT;
+                (age < MAX_AGE as usize).then(|| (word & !AGE_MASK) | ((age + 1) << AGE_SHIFT))
+            });
+    }
+
+    pub fn spare_bits(&self) -> u32 {
+        ((self.load() & SPARE_MASK) >> SPARE_SHIFT) as u32
+    }
+
+    /// Replace spare bits of the mark word, lock state and age are kept intact. Only `SPARE_BITS` lowest bits of `bits` are used.
+    pub fn set_spare_bits(&self, bits: u32) {
+        let bits = ((bits as usize) << SPARE_SHIFT) & SPARE_MASK;
+        let _ = self
+            .word
+            .fetch_update(Ordering::AcqRel, Ordering::Relaxed, |word| {
+                Some((word & !SPARE_MASK) | bits)
+            });
+    }
+}
@@ -1,3 +1,246 @@
 //! # nanbox
 //!
 //! A simple nan-boxing implementation for VMs.
+//!
+//! [`Value`] packs a double, an `i32`, a boolean, null, undefined or an object reference into 64 bits. Doubles are stored
+//! as is, with every NaN canonicalized to [`CANONICAL_NAN`]. Other values live in the negative quiet NaN space,
+//! upper 16 bits are the tag and lower 48 bits are the payload:
+//!
+//! ```text
+//! 0xFFF9 | i32       int32
+//! 0xFFFA | 0 or 1    boolean
+//! 0xFFFB | 0         null
+//! 0xFFFC | 0         undefined
+//! 0xFFFD | pointer   object reference (48-bit address)
+//! ```
+//!
+//! Values that hold object references must be visible to GC: use [`Visitor::visit_value`](crate::mm::scanning::Visitor::visit_value)
+//! or [`Tracer::trace_value`](crate::mm::scanning::Tracer::trace_value) in scan functions and register values on shadow stack
+//! as roots. Both require [`Runtime::Slot`] to be constructible from [`ValueSlot`], [`ValueOrObjectSlot`] is a
+//! ready-made slot implementation. Values are tagged words of [`NanBoxScheme`], so the generic
+//! [tagged slots](crate::mm::slot::TaggedSlot) work with them as well.
+
+use std::fmt;
+
+use mmtk::util::{Address, ObjectReference};
+
+use crate::{
+    mm::slot::{TagScheme, TaggedOrObjectSlot, TaggedSlot},
+    Runtime,
+};
+
+pub const TAG_SHIFT: u64 = 48;
+pub const PAYLOAD_MASK: u64 = (1 << TAG_SHIFT) - 1;
+pub const TAG_MASK: u64 = !PAYLOAD_MASK;
+
+pub const TAG_INT32: u64 = 0xFFF9 << TAG_SHIFT;
+pub const TAG_BOOL: u64 = 0xFFFA << TAG_SHIFT;
+pub const TAG_NULL: u64 = 0xFFFB << TAG_SHIFT;
+pub const TAG_UNDEFINED: u64 = 0xFFFC << TAG_SHIFT;
+pub const TAG_OBJECT: u64 = 0xFFFD << TAG_SHIFT;
+
+/// The only NaN bit pattern a boxed double can have. Lowest tag is above it, so doubles never look like tagged values.
+pub const CANONICAL_NAN: u64 = 0x7FF8_0000_0000_0000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(transparent)]
+pub struct Value(u64);
+
+impl Value {
+    pub const NULL: Self = Self(TAG_NULL);
+    pub const UNDEFINED: Self = Self(TAG_UNDEFINED);
+    pub const TRUE: Self = Self(TAG_BOOL | 1);
+    pub const FALSE: Self = Self(TAG_BOOL);
+
+    /// Value from raw bits. `bits` must come from [`raw`](Self::raw).
+    pub const fn from_raw(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub const fn raw(self) -> u64 {
+        self.0
+    }
+
+    pub fn new_f64(value: f64) -> Self {
+        if value.is_nan() {
+            Self(CANONICAL_NAN)
+        } else {
+            Self(value.to_bits())
+        }
+    }
+
+    pub const fn new_i32(value: i32) -> Self {
+        Self(TAG_INT32 | value as u32 as u64)
+    }
+
+    pub const fn new_bool(value: bool) -> Self {
+        Self(TAG_BOOL | value as u64)
+    }
+
+    pub fn new_object(object: ObjectReference) -> Self {
+        let address = object.to_raw_address().as_usize() as u64;
+        debug_assert!(
+            address & TAG_MASK == 0,
+            "object {} does not fit into 48 bits",
+            object
+        );
+        Self(TAG_OBJECT | address)
+    }
+
+    const fn tag(self) -> u64 {
+        self.0 & TAG_MASK
+    }
+
+    pub const fn is_f64(self) -> bool {
+        self.0 < TAG_INT32
+    }
+
+    pub const fn is_i32(self) -> bool {
+        self.tag() == TAG_INT32
+    }
+
+    pub const fn is_number(self) -> bool {
+        self.is_f64() || self.is_i32()
+    }
+
+    pub const fn is_bool(self) -> bool {
+        self.tag() == TAG_BOOL
+    }
+
+    pub const fn is_null(self) -> bool {
+        self.0 == TAG_NULL
+    }
+
+    pub const fn is_undefined(self) -> bool {
+        self.0 == TAG_UNDEFINED
+    }
+
+    pub const fn is_null_or_undefined(self) -> bool {
+        self.is_null() || self.is_undefined()
+    }
+
+    pub const fn is_object(self) -> bool {
+        self.tag() == TAG_OBJECT
+    }
+
+    pub fn as_f64(self) -> Option<f64> {
+        self.is_f64().then(|| f64::from_bits(self.0))
+    }
+
+    pub const fn as_i32(self) -> Option<i32> {
+        if self.is_i32() {
+            Some(self.0 as u32 as i32)
+        } else {
+            None
+        }
+    }
+
+    pub const fn as_bool(self) -> Option<bool> {
+        if self.is_bool() {
+            Some(self.0 & 1 != 0)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_object(self) -> Option<ObjectReference> {
+        if !self.is_object() {
+            return None;
+        }
+
+        ObjectReference::from_raw_address(unsafe {
+            Address::from_usize((self.0 & PAYLOAD_MASK) as usize)
+        })
+    }
+
+    /// Numeric value of an `i32` or a double.
+    pub fn to_number(self) -> Option<f64> {
+        self.as_i32()
+            .map(|value| value as f64)
+            .or_else(|| self.as_f64())
+    }
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Self::UNDEFINED
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Self::new_f64(value)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(value: i32) -> Self {
+        Self::new_i32(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Self::new_bool(value)
+    }
+}
+
+impl From<ObjectReference> for Value {
+    fn from(value: ObjectReference) -> Self {
+        Self::new_object(value)
+    }
+}
+
+/// `None` is boxed as null.
+impl From<Option<ObjectReference>> for Value {
+    fn from(value: Option<ObjectReference>) -> Self {
+        value.map_or(Self::NULL, Self::new_object)
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(value) = self.as_i32() {
+            write!(f, "Value({})", value)
+        } else if let Some(value) = self.as_f64() {
+            write!(f, "Value({:?})", value)
+        } else if let Some(value) = self.as_bool() {
+            write!(f, "Value({})", value)
+        } else if self.is_null() {
+            write!(f, "Value(null)")
+        } else if self.is_undefined() {
+            write!(f, "Value(undefined)")
+        } else if let Some(object) = self.as_object() {
+            write!(f, "Value({})", object)
+        } else {
+            write!(f, "Value({:#x})", self.0)
+        }
+    }
+}
+
+/// [`TagScheme`] of NaN-boxed values: words tagged with [`TAG_OBJECT`] hold object references, everything else is
+/// an immediate.
+pub struct NanBoxScheme;
+
+impl TagScheme for NanBoxScheme {
+    fn decode(word: usize) -> Option<ObjectReference> {
+        Value::from_raw(word as u64).as_object()
+    }
+
+    fn encode(_old: usize, object: ObjectReference) -> usize {
+        Value::new_object(object).raw() as usize
+    }
+}
+
+/// A slot that holds a [`Value`]. Loads `None` unless the value is an object reference, stores keep the object tag.
+pub type ValueSlot<R> = TaggedSlot<R, NanBoxScheme>;
+
+/// A slot that can hold either a plain object reference or a [`Value`]. Runtimes whose objects have both kinds
+/// of fields can use it as [`Runtime::Slot`].
+pub type ValueOrObjectSlot<R> = TaggedOrObjectSlot<R, NanBoxScheme>;
+
+impl<R: Runtime> TaggedSlot<R, NanBoxScheme> {
+    pub fn from_value(value: &Value) -> Self {
+        Self::from_address(Address::from_ref(value))
+    }
+}
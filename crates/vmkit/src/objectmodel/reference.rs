@@ -91,6 +91,57 @@ impl<'gc, U, OtherWeaknessTag, T, WeaknessTag> PartialEq<BasicMember<'gc, U, Oth
     }
 }
 
+/// Same as [`BasicMember`] but stores compressed 32-bit reference, see [`ptr_compr`](crate::mm::ptr_compr).
+///
+/// Requires runtime slot type to be constructible from [`CompressedSlot`](crate::mm::ptr_compr::CompressedSlot),
+/// e.g [`CompressibleSlot`](crate::mm::ptr_compr::CompressibleSlot).
+#[cfg(feature = "compressed-oops")]
+#[repr(transparent)]
+pub struct CompressedMember<'gc, T, WeaknessTag> {
+    pointer: std::sync::atomic::AtomicU32,
+    marker: PhantomData<(&'gc T, WeaknessTag)>,
+}
+
+#[cfg(feature = "compressed-oops")]
+impl<'gc, T, WeaknessTag> CompressedMember<'gc, T, WeaknessTag> {
+    pub fn slot<R: Runtime>(&self) -> R::Slot
+    where
+        R::Slot: From<crate::mm::ptr_compr::CompressedSlot>,
+    {
+        crate::mm::ptr_compr::CompressedSlot::from_address(Address::from_ref(self)).into()
+    }
+
+    pub fn from_object_reference(objref: Option<ObjectReference>) -> Self {
+        Self {
+            pointer: std::sync::atomic::AtomicU32::new(crate::mm::ptr_compr::compress(objref)),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.pointer.load(Ordering::Relaxed) == 0
+    }
+
+    /// Raw compressed value.
+    pub fn compressed(&self) -> u32 {
+        self.pointer.load(Ordering::Relaxed)
+    }
+
+    pub fn object_reference(&self) -> Option<ObjectReference> {
+        crate::mm::ptr_compr::decompress(self.pointer.load(Ordering::Relaxed))
+    }
+
+    pub fn write(&self, objref: Option<ObjectReference>) {
+        self.pointer
+            .store(crate::mm::ptr_compr::compress(objref), Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "compressed-oops")]
+pub type CompressedStrongMember<'gc, T> = CompressedMember<'gc, T, StrongMemberTag>;
+#[cfg(feature = "compressed-oops")]
+pub type CompressedWeakMember<'gc, T> = CompressedMember<'gc, T, WeakMemberTag>;
+
 pub struct StrongMemberTag;
 pub struct WeakMemberTag;
 pub struct UntracedMemberTag;
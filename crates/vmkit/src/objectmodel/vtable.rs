@@ -38,6 +38,11 @@ pub trait VTable<R: Runtime> {
     fn from_object_reference(_objref: ObjectReference) -> VTablePointer {
         unimplemented!()
     }
+
+    /// Human-readable name of objects with this vtable, e.g class name. Used by heap snapshots.
+    fn name(&self) -> Option<&str> {
+        None
+    }
 }
 #[cfg(target_pointer_width = "64")]
 pub const MAX_VTABLE_PTR: usize = 1 << 58;
@@ -1,27 +1,36 @@
 use std::{
     marker::PhantomData,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
 use mmtk::{
-    util::{alloc::AllocationError, options::PlanSelector, Address, ObjectReference, VMThread},
-    vm::{
-        slot::{Slot, UnimplementedMemorySlice},
-        ReferenceGlue, RootsWorkFactory, VMBinding,
-    },
+    util::{alloc::AllocationError, Address, ObjectReference, VMMutatorThread, VMThread},
+    vm::{slot::Slot, RootsWorkFactory, VMBinding},
     MMTKBuilder, MMTK,
 };
-use options::mmtk_options;
+use options::{mmtk_options, mmtkflags_verify_heap};
 use threads::Threads;
 
 use crate::{
-    mm::{scanning::VMScanning, slot::SlotExt, GENERATIONAL_PLAN},
-    objectmodel::vtable::VTable,
+    mm::{
+        barriers::{set_barrier_kind, BarrierKind},
+        finalization::Finalization,
+        references::ReferenceQueue,
+        sampling::AllocationProfile,
+        scanning::VMScanning,
+        shadow_stack::ShadowStackPool,
+        slot::SlotExt,
+        stats::{GcEvent, GcStatsCollector},
+    },
+    objectmodel::{mark_word::MarkWord, vtable::VTable},
+    sync::synchronizer::ObjectSynchronizer,
 };
 
 pub mod options;
 pub mod signals;
 pub mod threads;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub mod unwind;
 
 pub trait Runtime: 'static + Default + Send + Sync {
     type Slot: Slot + SlotExt<Self>;
@@ -45,6 +54,31 @@ pub trait Runtime: 'static + Default + Send + Sync {
     ///```
     const VO_BIT: bool = false;
 
+    /// Scan stacks of mutator threads conservatively. Every word on the stack that points to a valid object
+    /// (according to VO-bit metadata) is reported as a pinned root before [`Thread::scan_roots`](threads::Thread::scan_roots) is invoked.
+    ///
+    /// Requires [`VO_BIT`](Self::VO_BIT) to be true and `vo-bit` feature to be enabled.
+    const CONSERVATIVE_STACK_SCAN: bool = false;
+
+    /// Scan JIT-compiled frames of mutator threads precisely using stack maps registered in
+    /// [`STACK_MAPS`](crate::mm::stack_map::STACK_MAPS). Frames are walked with [`unwind`](crate::runtime::unwind),
+    /// JIT code must keep frame pointers. Supported on x86-64 and AArch64.
+    const SCAN_JIT_FRAMES: bool = false;
+
+    /// Put a [mark word](crate::objectmodel::mark_word) in front of every object header. Mark word holds lock state
+    /// used by [`synchronizer`](crate::sync::synchronizer), object age and spare bits for the VM. Costs one word per object.
+    const MARK_WORD: bool = false;
+
+    /// Support [reference objects](crate::mm::references). Runtime that sets it must implement
+    /// [`referent_slot`](Self::referent_slot) and [`clear_referent`](Self::clear_referent). Otherwise MMTk reference
+    /// processing is turned off and registering a reference object is a compile-time error.
+    const SUPPORTS_REFERENCES: bool = false;
+
+    /// Objects have a lock word, see [`lock_word`](Self::lock_word). Required by [`synchronizer`](crate::sync::synchronizer),
+    /// using it without a lock word is a compile-time error. Set by default when [`MARK_WORD`](Self::MARK_WORD) is enabled,
+    /// runtime that overrides `lock_word` must set it too.
+    const LOCK_WORD: bool = Self::MARK_WORD;
+
     /// An accessor for thread-local storage of current thread. You can simply use `thread_local!` and return
     /// pointer to it.
     fn current_thread() -> VMThread {
@@ -103,6 +137,37 @@ pub trait Runtime: 'static + Default + Send + Sync {
         false
     }
 
+    /// Slot that holds referent of a reference object, e.g `java.lang.ref.Reference::referent`. Invoked only
+    /// for objects registered with [`register_reference`](crate::mm::references::register_reference), must be
+    /// implemented when [`SUPPORTS_REFERENCES`](Self::SUPPORTS_REFERENCES) is set.
+    fn referent_slot(reference: ObjectReference) -> Self::Slot {
+        let _ = reference;
+        unimplemented!("referent_slot must be implemented when SUPPORTS_REFERENCES is set")
+    }
+
+    /// Clear referent of a reference object i.e store null into [`referent_slot`](Self::referent_slot).
+    fn clear_referent(reference: ObjectReference) {
+        let _ = reference;
+        unimplemented!("clear_referent must be implemented when SUPPORTS_REFERENCES is set")
+    }
+
+    /// Address of the lock word of `object` used by [`ObjectSynchronizer`](crate::sync::synchronizer::ObjectSynchronizer).
+    /// Word must be zero on allocation. Synchronizer owns its two lowest and 32 highest bits, the rest are left to the VM.
+    ///
+    /// Defaults to the [mark word](crate::objectmodel::mark_word) when [`MARK_WORD`](Self::MARK_WORD) is enabled.
+    fn lock_word(object: ObjectReference) -> Address {
+        if Self::MARK_WORD {
+            Address::from_ref(MarkWord::from_object::<Self>(object))
+        } else {
+            unimplemented!("lock_word must be implemented when LOCK_WORD is set")
+        }
+    }
+
+    /// Invoked on GC start, end and phase transitions, see [`stats`](crate::mm::stats).
+    fn gc_event(event: GcEvent<'_>) {
+        let _ = event;
+    }
+
     fn vmkit() -> &'static VMKit<Self>;
 }
 
@@ -110,6 +175,12 @@ pub struct VMKit<R: Runtime> {
     pub mmtk: MMTK<MMTKVMKit<R>>,
     pub(crate) scanning: crate::mm::scanning::VMScanning<R>,
     pub(crate) threads: threads::Threads<R>,
+    pub finalization: Finalization<R>,
+    pub references: ReferenceQueue<R>,
+    pub(crate) gc_stats: GcStatsCollector,
+    pub(crate) allocation_profile: AllocationProfile,
+    pub shadow_stacks: ShadowStackPool<R>,
+    pub synchronizer: ObjectSynchronizer<R>,
 }
 
 unsafe impl<R: Runtime> Sync for VMKit<R> {}
@@ -136,18 +207,42 @@ where
         self
     }
 
-    pub fn build(self) -> VMKit<R> {
-        GENERATIONAL_PLAN.store(
-            matches!(
-                *self.mmtk_builder.options.plan,
-                PlanSelector::GenCopy | PlanSelector::GenImmix | PlanSelector::StickyImmix
-            ),
-            Ordering::Relaxed,
+    /// Reserve MMTk heap so that every object can be referenced by compressed pointers, see [`ptr_compr`](crate::mm::ptr_compr).
+    /// Overrides heap layout set on [`mmtk_builder`](Self::mmtk_builder).
+    #[cfg(feature = "compressed-oops")]
+    pub fn compressed_oops(mut self) -> Self {
+        crate::mm::ptr_compr::initialize_heap_layout(&mut self.mmtk_builder);
+        self
+    }
+
+    pub fn build(mut self) -> VMKit<R> {
+        assert!(
+            !R::CONSERVATIVE_STACK_SCAN || (R::VO_BIT && cfg!(feature = "vo-bit")),
+            "conservative stack scanning requires VO bits"
+        );
+        assert!(
+            !*mmtkflags_verify_heap() || (R::VO_BIT && cfg!(feature = "vo-bit")),
+            "verify_heap requires VO bits"
+        );
+        assert!(
+            !R::SCAN_JIT_FRAMES || cfg!(any(target_arch = "x86_64", target_arch = "aarch64")),
+            "JIT frames can't be unwound on this architecture"
         );
+        if !R::SUPPORTS_REFERENCES {
+            // No reference objects can be registered, don't schedule reference processing work.
+            self.mmtk_builder.options.no_reference_types.set(true);
+        }
+        set_barrier_kind(BarrierKind::from_plan(*self.mmtk_builder.options.plan));
         VMKit {
             mmtk: self.mmtk_builder.build(),
             scanning: VMScanning::default(),
             threads: Threads::new(),
+            finalization: Finalization::new(),
+            references: ReferenceQueue::new(),
+            gc_stats: GcStatsCollector::new(),
+            allocation_profile: AllocationProfile::new(),
+            shadow_stacks: ShadowStackPool::new(),
+            synchronizer: ObjectSynchronizer::new(),
         }
     }
 }
@@ -160,55 +255,69 @@ impl<R: Runtime> VMBinding for MMTKVMKit<R> {
     type VMScanning = crate::mm::scanning::VMScanning<R>;
     type VMActivePlan = crate::mm::active_plan::VMActivePlan<R>;
     type VMCollection = crate::mm::collection::VMCollection<R>;
-    type VMMemorySlice = UnimplementedMemorySlice<R::Slot>;
-    type VMReferenceGlue = UnimplementedRefGlue<R>;
+    type VMMemorySlice = crate::mm::memory_slice::VMMemorySlice<R>;
+    type VMReferenceGlue = crate::mm::references::VMReferenceGlue<R>;
     type VMSlot = R::Slot;
 
     const MAX_ALIGNMENT: usize = size_of::<usize>() * 2;
     const MIN_ALIGNMENT: usize = size_of::<usize>();
 }
 
-pub struct DisableGCScope;
+/// Disables garbage collection while alive.
+///
+/// Scope is global: while any scope is alive in any thread no new collection is triggered. Scopes nest.
+///
+/// A collection that was triggered before [`new`](Self::new) is not cancelled: it still stops the world and completes,
+/// blocking the thread at its next yieldpoint even though the scope is alive. Code that must not observe objects moving
+/// has to pin them, see [`pinning`](crate::mm::pinning).
+///
+/// While collections are disabled allocation keeps succeeding by growing the heap past its limit. If memory can't
+/// be acquired at all [`Runtime::out_of_memory`] is invoked, [`is_gc_disabled`](Self::is_gc_disabled) tells it apart from
+/// a regular out of memory condition. GC requests made while disabled (see [`request_gc`](Self::request_gc)) are deferred
+/// and performed by the thread that drops the last scope. Allocation-triggered collections simply happen on the next
+/// allocation slow path once GC is enabled again.
+pub struct DisableGCScope<R: Runtime> {
+    /// Scope must be dropped on the thread that created it, deferred GC is requested from that thread.
+    marker: PhantomData<(R, *const ())>,
+}
 
 static DISABLED_GC_SCOPE: AtomicUsize = AtomicUsize::new(0);
+static DEFERRED_GC_REQUEST: AtomicBool = AtomicBool::new(false);
 
-impl DisableGCScope {
+impl<R: Runtime> DisableGCScope<R> {
     pub fn new() -> Self {
         DISABLED_GC_SCOPE.fetch_add(1, Ordering::AcqRel);
-        Self
+        Self {
+            marker: PhantomData,
+        }
     }
 
     pub fn is_gc_disabled() -> bool {
         DISABLED_GC_SCOPE.load(Ordering::Acquire) != 0
     }
-}
 
-impl Drop for DisableGCScope {
-    fn drop(&mut self) {
-        DISABLED_GC_SCOPE.fetch_sub(1, Ordering::AcqRel);
-    }
-}
-
-/// Reference glue is not implemented. We have our own weak refs & finalizers processing.
-pub struct UnimplementedRefGlue<R: Runtime>(PhantomData<R>);
+    /// Request GC from mutator `thread`. Performed immediately unless collections are disabled,
+    /// otherwise it is deferred until the last scope ends.
+    pub fn request_gc(thread: VMMutatorThread) {
+        DEFERRED_GC_REQUEST.store(true, Ordering::Release);
 
-impl<R: Runtime> ReferenceGlue<MMTKVMKit<R>> for UnimplementedRefGlue<R> {
-    type FinalizableType = ObjectReference;
-    fn clear_referent(_new_reference: mmtk::util::ObjectReference) {
-        todo!()
-    }
-    fn enqueue_references(
-        _references: &[mmtk::util::ObjectReference],
-        _tls: mmtk::util::VMWorkerThread,
-    ) {
-        todo!()
+        // scope could end between store and check, whoever takes the request performs it
+        if !Self::is_gc_disabled() {
+            Self::perform_deferred_gc(thread);
+        }
     }
 
-    fn get_referent(_object: mmtk::util::ObjectReference) -> Option<mmtk::util::ObjectReference> {
-        todo!()
+    fn perform_deferred_gc(thread: VMMutatorThread) {
+        if DEFERRED_GC_REQUEST.swap(false, Ordering::AcqRel) {
+            mmtk::memory_manager::handle_user_collection_request(&R::vmkit().mmtk, thread);
+        }
     }
+}
 
-    fn set_referent(_reff: mmtk::util::ObjectReference, _referent: mmtk::util::ObjectReference) {
-        todo!()
+impl<R: Runtime> Drop for DisableGCScope<R> {
+    fn drop(&mut self) {
+        if DISABLED_GC_SCOPE.fetch_sub(1, Ordering::AcqRel) == 1 {
+            Self::perform_deferred_gc(VMMutatorThread(threads::vmkit_current_thread()));
+        }
     }
 }
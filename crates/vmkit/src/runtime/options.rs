@@ -68,6 +68,34 @@ define_flag!(MMTKFlags =>
     "Number of GC worker threads. (default: number of cores)"
 );
 
+define_flag!(MMTKFlags =>
+    usize,
+    gc_stress,
+    0,
+    "Debug: force GC every N allocations, 0 disables stress mode. (default: 0)"
+);
+
+define_flag!(MMTKFlags =>
+    bool,
+    gc_stress_yieldpoints,
+    false,
+    "Debug: force GC at every yieldpoint. (default: false)"
+);
+
+define_flag!(MMTKFlags =>
+    bool,
+    verify_heap,
+    false,
+    "Debug: verify heap after each GC, requires VO bits. (default: false)"
+);
+
+define_flag!(MMTKFlags =>
+    MemorySize,
+    alloc_sampling_interval,
+    MemorySize(0),
+    "Record allocation sample every N allocated bytes, 0 disables sampling. (default: 0)"
+);
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum SelectedGCPlan {
     None,
@@ -1,4 +1,9 @@
-use crate::{mm::tlab::TLAB, sync::Monitor, MMTKVMKit, Runtime, ThreadOf};
+use crate::{
+    arch::{save_registers, SavedRegisters, NUM_SAVED_REGISTERS},
+    mm::{shadow_stack::ShadowStack, stress, tlab::TLAB},
+    sync::{lock_stack::LockStack, Monitor},
+    MMTKVMKit, Runtime, ThreadOf,
+};
 use mmtk::{
     util::{Address, OpaquePointer, VMMutatorThread, VMThread},
     vm::RootsWorkFactory,
@@ -7,7 +12,7 @@ use mmtk::{
 use std::{
     cell::{RefCell, UnsafeCell},
     marker::PhantomData,
-    mem::MaybeUninit,
+    mem::{offset_of, MaybeUninit},
     panic::AssertUnwindSafe,
     sync::{
         atomic::{AtomicBool, AtomicI32, AtomicI8, AtomicU8, AtomicUsize, Ordering},
@@ -15,6 +20,7 @@ use std::{
     },
     thread::JoinHandle,
 };
+use swapstack::stack_bounds::StackBounds;
 
 /// A thread in the runtime that uses VMKit. This trait
 /// represents a type that can hold VMKit TLS data which is necessary
@@ -89,7 +95,10 @@ pub trait Thread<R: Runtime>: 'static {
                 (tls.mutator.as_ptr() as *mut Box<Mutator<_>>).write(mutator);
             }
             THREAD.with_borrow_mut(|thr| *thr = thread);
-            if main_thread() == VMThread::UNINITIALIZED {
+            tls.stack_base
+                .store(StackBounds::current().origin() as usize, Ordering::Relaxed);
+            let is_main = main_thread() == VMThread::UNINITIALIZED;
+            if is_main {
                 MAIN_THREAD.store(thread.0.to_address().as_usize(), Ordering::Relaxed);
                 R::vmkit().threads.add_main_thread(thread);
             } else {
@@ -99,6 +108,11 @@ pub trait Thread<R: Runtime>: 'static {
             tls.set_state(ThreadState::Running);
 
             ThreadOf::<R>::enable_yieldpoints(VMMutatorThread(thread));
+            stress::arm_yieldpoint::<R>(VMMutatorThread(thread));
+
+            if is_main {
+                R::vmkit().finalization.start_thread();
+            }
 
             let result = std::panic::catch_unwind(AssertUnwindSafe(|| callback(thread)));
             terminate_thread::<R>();
@@ -184,6 +198,7 @@ pub trait Thread<R: Runtime>: 'static {
     ////
     fn check_block(thread: VMThread) {
         if Self::is_mutator(thread) {
+            Self::tls(thread).save_stack_state();
             Self::save_thread_state();
         }
         Self::check_block_no_save_context(thread);
@@ -329,11 +344,13 @@ pub trait Thread<R: Runtime>: 'static {
     /// actions (such as handling handshake requests, which may include things like
     /// mutator flushes and running isync) that [`Parked`](ThreadState::Parked) code will not perform until
     /// returning to [`Running`](ThreadState::Running) by way of a [`leave_native()`](Self::leave_parked) call.
-
-    #[inline(never)]
+    ///
+    /// Always inlined: stack state is saved in the frame of the caller, which stays alive while the thread is parked.
+    #[inline(always)]
     fn enter_parked() {
         let t = R::current_thread();
         let tls = Self::tls(t);
+        tls.save_stack_state();
         let mut old_state;
         let mut new_state;
 
@@ -487,6 +504,8 @@ pub trait Thread<R: Runtime>: 'static {
 
         tls.at_yieldpoint.store(false, Ordering::Relaxed);
 
+        stress::on_yieldpoint::<R>(VMMutatorThread(t));
+
         Self::yieldpoint_unblocked_no_lock(VMMutatorThread(t), where_from, yieldpoint_fp)
     }
 
@@ -539,6 +558,10 @@ pub unsafe fn terminate_thread<R: Runtime>() {
             mmtk::memory_manager::destroy_mutator(&mut **mutator);
         }
         let _ = unsafe { mutator.read() };
+
+        if let Some(shadow_stack) = unsafe { (*tls.shadow_stack.get()).take() } {
+            R::vmkit().shadow_stacks.release(shadow_stack);
+        }
     }
 
     R::vmkit().threads.remove_current_thread();
@@ -626,6 +649,8 @@ pub struct TLSData<R: Runtime> {
     /// A statistic counter that contains the number of taken yieldpoints that is every single time when `yieldpoint` method
     /// was invoked.
     pub yieldpoints_taken: AtomicUsize,
+    /// Number of allocations made by this thread, counted only when `gc_stress` is set, see [`stress`](crate::mm::stress).
+    pub stress_allocations: AtomicUsize,
     /// Is yieldpoint request pending on this thread? It's only set by `enable_yieldpoints` and `disable_yieldpoints`.
     pub yieldpoint_request_pending: AtomicBool,
     pub at_yieldpoint: AtomicBool,
@@ -642,6 +667,19 @@ pub struct TLSData<R: Runtime> {
     pub is_about_to_terminate: AtomicBool,
 
     pub index_in_thread_list: AtomicUsize,
+    /// Highest address of the native stack this thread runs on. Set once thread is started.
+    pub stack_base: AtomicUsize,
+    /// Stack pointer at the moment thread was blocked or entered parked state. Stack between
+    /// this value and `stack_base` is what conservative stack scanning walks.
+    pub saved_sp: AtomicUsize,
+    /// Instruction pointer saved together with `saved_sp`, stack of a blocked thread is unwound from it.
+    pub saved_ip: AtomicUsize,
+    /// Callee-saved registers spilled together with `saved_sp`.
+    pub saved_registers: UnsafeCell<SavedRegisters>,
+    /// Shadow stack of a mutator thread, scanned automatically during GC. Returned to the pool once thread terminates.
+    shadow_stack: UnsafeCell<Option<Box<ShadowStack<R>>>>,
+    /// Objects fast-locked by this thread, see [`synchronizer`](crate::sync::synchronizer).
+    pub lock_stack: UnsafeCell<LockStack<R>>,
 }
 
 impl<R: Runtime> Default for TLSData<R> {
@@ -651,6 +689,12 @@ impl<R: Runtime> Default for TLSData<R> {
 }
 
 impl<R: Runtime> TLSData<R> {
+    /// Offset of TLAB bump cursor from the start of `TLSData`. `TLSData` is `#[repr(C)]` so
+    /// the offset is stable and can be embedded into JIT code.
+    pub const TLAB_CURSOR_OFFSET: usize = offset_of!(Self, tlab) + TLAB::<R>::CURSOR_OFFSET;
+    /// Offset of TLAB bump limit from the start of `TLSData`.
+    pub const TLAB_LIMIT_OFFSET: usize = offset_of!(Self, tlab) + TLAB::<R>::LIMIT_OFFSET;
+
     pub fn new(is_mutator: bool) -> Self {
         Self {
             tlab: UnsafeCell::new(TLAB::<R>::new()),
@@ -662,6 +706,7 @@ impl<R: Runtime> TLSData<R> {
             at_yieldpoint: AtomicBool::new(false),
             yieldpoints_taken_fully: AtomicUsize::new(0),
             yieldpoints_taken: AtomicUsize::new(0),
+            stress_allocations: AtomicUsize::new(0),
             is_about_to_terminate: AtomicBool::new(false),
             is_generational: R::vmkit().mmtk.get_plan().generational().is_some(),
             is_blocking: AtomicBool::new(false),
@@ -672,13 +717,73 @@ impl<R: Runtime> TLSData<R> {
 
             state: AtomicU8::new(ThreadState::Running as _),
             is_active_mutator_context: AtomicBool::new(is_mutator),
+            stack_base: AtomicUsize::new(0),
+            saved_sp: AtomicUsize::new(0),
+            saved_ip: AtomicUsize::new(0),
+            saved_registers: UnsafeCell::new([0; NUM_SAVED_REGISTERS]),
+            shadow_stack: UnsafeCell::new(is_mutator.then(|| R::vmkit().shadow_stacks.acquire())),
+            lock_stack: UnsafeCell::new(LockStack::new()),
         }
     }
 
+    /// Record current stack pointer, instruction pointer and callee-saved registers. Must be invoked by the thread
+    /// itself before it enters a state in which GC can scan its stack.
+    ///
+    /// Always inlined: saved state describes the frame of the caller, caller must not return until the thread
+    /// leaves blocked or parked state.
+    #[inline(always)]
+    pub fn save_stack_state(&self) {
+        let (sp, ip) = unsafe { save_registers(&mut *self.saved_registers.get()) };
+        self.saved_ip.store(ip.as_usize(), Ordering::Relaxed);
+        self.saved_sp.store(sp.as_usize(), Ordering::Release);
+    }
+
+    /// Stack range `[saved_sp, stack_base)` of a blocked thread. Returns `None` if thread
+    /// was never started or never blocked.
+    pub fn saved_stack_range(&self) -> Option<(Address, Address)> {
+        let sp = self.saved_sp.load(Ordering::Acquire);
+        let base = self.stack_base.load(Ordering::Relaxed);
+
+        if sp == 0 || base == 0 || sp > base {
+            return None;
+        }
+
+        unsafe { Some((Address::from_usize(sp), Address::from_usize(base))) }
+    }
+
+    /// Instruction pointer captured by the last [`save_stack_state`](Self::save_stack_state) call. Only
+    /// meaningful if [`saved_stack_range`](Self::saved_stack_range) is available.
+    pub fn saved_ip(&self) -> Address {
+        unsafe { Address::from_usize(self.saved_ip.load(Ordering::Relaxed)) }
+    }
+
+    /// Callee-saved registers captured by the last [`save_stack_state`](Self::save_stack_state) call.
+    ///
+    /// # Safety
+    ///
+    /// Thread must be blocked, otherwise registers are racing with the thread itself.
+    pub unsafe fn saved_registers(&self) -> &SavedRegisters {
+        &*self.saved_registers.get()
+    }
+
+    /// Shadow stack of this thread. Panics if thread is not a mutator or already terminated.
+    pub fn shadow_stack(&self) -> &ShadowStack<R> {
+        self.shadow_stack_opt()
+            .expect("thread does not have a shadow stack")
+    }
+
+    pub fn shadow_stack_opt(&self) -> Option<&ShadowStack<R>> {
+        unsafe { (*self.shadow_stack.get()).as_deref() }
+    }
+
     pub unsafe fn tlab_mut_unchecked(&self) -> &mut TLAB<R> {
         &mut *self.tlab.get()
     }
 
+    pub unsafe fn lock_stack_mut_unchecked(&self) -> &mut LockStack<R> {
+        &mut *self.lock_stack.get()
+    }
+
     pub unsafe fn mutator_mut_unchecked(&self) -> &mut Mutator<MMTKVMKit<R>> {
         &mut *self.mutator.assume_init_ref().get()
     }
@@ -836,6 +941,9 @@ pub struct Threads<R: Runtime> {
     pub barrier: Barrier,
     pub next_thread_id: AtomicUsize,
     pub handshake_threads: Monitor<Vec<VMThread>, R, true>,
+    /// Held from [`block_all_mutators_for_gc`] until [`unblock_all_mutators_for_gc`] so GC and
+    /// [`stop_the_world`] never interleave.
+    world_stopped: parking_lot::Mutex<()>,
     marker: PhantomData<R>,
 }
 
@@ -851,6 +959,7 @@ impl<R: Runtime> Threads<R> {
             threads: Mutex::new(Vec::new()),
             marker: PhantomData,
             handshake_threads: Monitor::new(Vec::new()),
+            world_stopped: parking_lot::Mutex::new(()),
         }
     }
 
@@ -1092,6 +1201,9 @@ block_adapter_list!((X0, X1)(X0, X1, X2)(X0, X1, X2, X3)(X0, X1, X2, X3, X4)(
 pub(crate) fn block_all_mutators_for_gc<R: Runtime>() {
     let threads = &R::vmkit().threads;
 
+    // released in `unblock_all_mutators_for_gc`
+    std::mem::forget(threads.world_stopped.lock());
+
     let mut handshake = threads.handshake_threads.lock_no_handshake();
 
     loop {
@@ -1159,6 +1271,27 @@ pub(crate) fn unblock_all_mutators_for_gc<R: Runtime>() {
     }
 
     drop(handshake);
+
+    unsafe {
+        threads.world_stopped.force_unlock();
+    }
+}
+
+/// Stop all mutator threads, run `callback` and resume them.
+///
+/// Must be invoked from a mutator thread. Current thread is parked while world is stopped, so `callback`
+/// must not allocate or otherwise touch GC state that requires running thread. No GC can happen until `callback` returns.
+pub fn stop_the_world<R: Runtime, T>(callback: impl FnOnce() -> T) -> T {
+    ThreadOf::<R>::enter_parked();
+    block_all_mutators_for_gc::<R>();
+    let result = std::panic::catch_unwind(AssertUnwindSafe(callback));
+    unblock_all_mutators_for_gc::<R>();
+    ThreadOf::<R>::leave_parked();
+
+    match result {
+        Ok(result) => result,
+        Err(err) => std::panic::resume_unwind(err),
+    }
 }
 
 thread_local! {
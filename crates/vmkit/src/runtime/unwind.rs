@@ -4,15 +4,24 @@
 //! Simple interface for unwinding on top of framehop. Implements methods to register custom modules
 //! and currently linked modules (to current process).
 //!
+//! Frames are unwound with unwind info of the modules, callee-saved registers of every frame are recovered
+//! together with stack and frame pointer. Code without registered unwind info (e.g JIT code) is unwound
+//! through frame pointers.
 
-use crate::arch::CalleeSaves;
-use crate::runtime::threads::stack::*;
-use framehop::{AllocationPolicy, Module, Unwinder as _, UnwinderNative};
+use std::{ops::Range, sync::LazyLock};
+
+use framehop::{AllocationPolicy, MayAllocateDuringUnwind, Module, Unwinder as _, UnwinderNative};
 use mmtk::util::Address;
 
+use crate::{
+    arch::{self, SavedRegisters},
+    runtime::threads::TLSData,
+    Runtime,
+};
+
 pub mod object;
 
-pub use framehop::{self, CacheNative, FrameAddress};
+pub use framehop::{self, CacheNative, FrameAddress, UnwindRegsNative};
 
 pub struct Unwinder<'a, P>
 where
@@ -21,6 +30,12 @@ where
     unwinder: UnwinderNative<&'a [u8], P>,
 }
 
+impl<'a, P: AllocationPolicy> Default for Unwinder<'a, P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'a, P: AllocationPolicy> Unwinder<'a, P> {
     pub fn new() -> Self {
         Self {
@@ -38,42 +53,138 @@ impl<'a, P: AllocationPolicy> Unwinder<'a, P> {
         self.unwinder.add_module(module);
     }
 
-    #[cfg(target_arch = "x86_64")]
-    pub fn iter_frames_of<'u, 'c>(
+    /// Iterate frames of a thread that is blocked for GC or parked, starting at the frame that saved
+    /// its stack state (see [`TLSData::save_stack_state`]). Returns `None` if thread never saved its state.
+    ///
+    /// # Safety
+    ///
+    /// Thread must stay blocked or parked while the iterator is used.
+    pub unsafe fn iter_frames_of<'u, 'c, R: Runtime>(
         &'u self,
-        stack: &Stack,
+        tls: &TLSData<R>,
         cache: &'c mut CacheNative<P>,
-    ) -> UnwindIterator<'u, 'c, UnwinderNative<&'a [u8], P>> {
-        let ip = stack.ip();
+    ) -> Option<UnwindIterator<'u, 'c, UnwinderNative<&'a [u8], P>>> {
+        let (sp, base) = tls.saved_stack_range()?;
+        let ip = tls.saved_ip();
+        let regs = unwind_regs(ip, sp, tls.saved_registers());
 
-        UnwindIterator::new(
-            &self.unwinder,
-            ip.as_usize() as _,
-            unsafe { stack.unwind_regs() },
-            cache,
+        Some(
+            UnwindIterator::new(&self.unwinder, ip.as_usize() as _, regs, cache)
+                .with_stack_range(sp, base),
         )
     }
 
-    #[cfg(target_arch = "x86_64")]
+    /// Iterate frames of the current thread, starting at the frame of the caller.
+    ///
+    /// Always inlined: the first frame must stay alive while the iterator is used.
+    #[inline(always)]
     pub fn iter_frames<'u, 'c>(
-        &'u mut self,
+        &'u self,
         cache: &'c mut CacheNative<P>,
     ) -> UnwindIterator<'u, 'c, UnwinderNative<&'a [u8], P>> {
-        use framehop::UnwindRegsNative;
-
-        #[allow(unused)]
-        let (rip, regs) = {
-            let mut rip = 0;
-            let mut rsp = 0;
-            let mut rbp = 0;
-            unsafe {
-                std::arch::asm!("lea {}, [rip]", out(reg) rip);
-                std::arch::asm!("mov {}, rsp", out(reg) rsp);
-                std::arch::asm!("mov {}, rbp", out(reg) rbp);
+        let mut registers = [0; arch::NUM_SAVED_REGISTERS];
+        let (sp, ip) = arch::save_registers(&mut registers);
+        let regs = unwind_regs(ip, sp, &registers);
+
+        UnwindIterator::new(&self.unwinder, ip.as_usize() as _, regs, cache)
+    }
+}
+
+static PROCESS_UNWINDER: LazyLock<Unwinder<'static, MayAllocateDuringUnwind>> =
+    LazyLock::new(|| {
+        let mut unwinder = Unwinder::new();
+        unwinder.add_current_module();
+        unwinder
+    });
+
+/// Unwinder for all the modules linked to the current process. Built on first use.
+pub fn process_unwinder() -> &'static Unwinder<'static, MayAllocateDuringUnwind> {
+    &PROCESS_UNWINDER
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "x86_64")] {
+        use framehop::x86_64::Reg;
+
+        /// Registers of [`SavedRegisters`] in the order they're saved in.
+        const SAVED_REGISTERS: [Reg; arch::NUM_SAVED_REGISTERS] = [
+            Reg::RBX,
+            Reg::RBP,
+            Reg::RSI,
+            Reg::RDI,
+            Reg::R12,
+            Reg::R13,
+            Reg::R14,
+            Reg::R15,
+        ];
+
+        fn unwind_regs(ip: Address, sp: Address, registers: &SavedRegisters) -> UnwindRegsNative {
+            let mut regs = UnwindRegsNative::new(
+                ip.as_usize() as _,
+                sp.as_usize() as _,
+                registers[arch::FRAME_POINTER_INDEX] as _,
+            );
+
+            for (reg, &value) in SAVED_REGISTERS.into_iter().zip(registers.iter()) {
+                regs.set(reg, value as _);
             }
-            (rip, UnwindRegsNative::new(rip, rsp, rbp))
-        };
-        UnwindIterator::new(&self.unwinder, rip, regs, cache)
+
+            regs
+        }
+
+        /// Frame pointer of the frame `regs` describe.
+        pub fn frame_pointer(regs: &UnwindRegsNative) -> Address {
+            unsafe { Address::from_usize(regs.bp() as usize) }
+        }
+
+        /// Callee-saved register with DWARF number `register`. `rbp` is left out, it holds the frame pointer.
+        fn callee_saved_register(register: u16) -> Option<Reg> {
+            match register {
+                3 => Some(Reg::RBX),
+                #[cfg(windows)]
+                4 => Some(Reg::RSI),
+                #[cfg(windows)]
+                5 => Some(Reg::RDI),
+                12 => Some(Reg::R12),
+                13 => Some(Reg::R13),
+                14 => Some(Reg::R14),
+                15 => Some(Reg::R15),
+                _ => None,
+            }
+        }
+
+        /// Is `register` (DWARF number) callee-saved, i.e can its value be recovered in caller frames?
+        pub fn is_callee_saved_register(register: u16) -> bool {
+            callee_saved_register(register).is_some()
+        }
+
+        /// Value of callee-saved `register` (DWARF number) in the frame `regs` describe.
+        pub fn register_value(regs: &UnwindRegsNative, register: u16) -> Option<usize> {
+            callee_saved_register(register).map(|reg| regs.get(reg) as usize)
+        }
+    } else if #[cfg(target_arch = "aarch64")] {
+        fn unwind_regs(_ip: Address, sp: Address, registers: &SavedRegisters) -> UnwindRegsNative {
+            // link register of the first frame is spilled, caller of `save_registers` is never a leaf function
+            UnwindRegsNative::new(0, sp.as_usize() as _, registers[arch::FRAME_POINTER_INDEX] as _)
+        }
+
+        /// Frame pointer of the frame `regs` describe.
+        pub fn frame_pointer(regs: &UnwindRegsNative) -> Address {
+            unsafe { Address::from_usize(regs.fp() as usize) }
+        }
+
+        /// Is `register` (DWARF number) callee-saved, i.e can its value be recovered in caller frames?
+        /// Only frame pointer and link register are recovered on aarch64.
+        pub fn is_callee_saved_register(register: u16) -> bool {
+            let _ = register;
+            false
+        }
+
+        /// Value of callee-saved `register` (DWARF number) in the frame `regs` describe.
+        pub fn register_value(regs: &UnwindRegsNative, register: u16) -> Option<usize> {
+            let _ = (regs, register);
+            None
+        }
     }
 }
 
@@ -88,6 +199,7 @@ pub struct UnwindIterator<'u, 'c, U: framehop::Unwinder + ?Sized> {
     state: UnwindIteratorState,
     regs: U::UnwindRegs,
     cache: &'c mut U::Cache,
+    stack: Range<u64>,
 }
 
 impl<'u, 'c, U: framehop::Unwinder + ?Sized> UnwindIterator<'u, 'c, U> {
@@ -98,9 +210,16 @@ impl<'u, 'c, U: framehop::Unwinder + ?Sized> UnwindIterator<'u, 'c, U> {
             state: UnwindIteratorState::Initial(pc),
             regs,
             cache,
+            stack: 0..u64::MAX,
         }
     }
 
+    /// Only read stack memory in `[start, end)`, unwinding fails once a frame points outside of it.
+    pub fn with_stack_range(mut self, start: Address, end: Address) -> Self {
+        self.stack = start.as_usize() as u64..end.as_usize() as u64;
+        self
+    }
+
     pub fn regs(&self) -> &U::UnwindRegs {
         &self.regs
     }
@@ -119,17 +238,22 @@ impl<'u, 'c, U: framehop::Unwinder + ?Sized> UnwindIterator<'u, 'c, U> {
     /// address could not be read.
     #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Result<Option<FrameAddress>, framehop::Error> {
+        let stack = self.stack.clone();
         let next = match self.state {
             UnwindIteratorState::Initial(pc) => {
                 self.state = UnwindIteratorState::Unwinding(FrameAddress::InstructionPointer(pc));
                 return Ok(Some(FrameAddress::InstructionPointer(pc)));
             }
-            UnwindIteratorState::Unwinding(address) => self.unwinder.unwind_frame(
-                address,
-                &mut self.regs,
-                self.cache,
-                &mut |addr| unsafe { Ok((addr as *const u64).read()) },
-            )?,
+            UnwindIteratorState::Unwinding(address) => {
+                self.unwinder
+                    .unwind_frame(address, &mut self.regs, self.cache, &mut |addr| {
+                        if addr % 8 == 0 && stack.contains(&addr) {
+                            unsafe { Ok((addr as *const u64).read()) }
+                        } else {
+                            Err(())
+                        }
+                    })?
+            }
             UnwindIteratorState::Done => return Ok(None),
         };
         match next {
@@ -146,60 +270,3 @@ impl<'u, 'c, U: framehop::Unwinder + ?Sized> UnwindIterator<'u, 'c, U> {
         }
     }
 }
-
-impl<'u, 'c, P: AllocationPolicy> super::osr::Unwinder
-    for UnwindIterator<'u, 'c, UnwinderNative<&'u [u8], P>>
-{
-    type Error = framehop::Error;
-    fn callee_saves(&mut self) -> crate::arch::CalleeSaves {
-        #[cfg(target_arch = "x86_64")]
-        {
-            use framehop::x86_64::Reg::*;
-            #[cfg(not(windows))]
-            {
-                CalleeSaves {
-                    r15: self.regs.get(R15),
-                    r14: self.regs.get(R14),
-                    r13: self.regs.get(R13),
-                    r12: self.regs.get(R12),
-                    rbx: self.regs.get(RBX),
-                    rbp: self.regs.get(RBP),
-                }
-            }
-
-            #[cfg(windows)]
-            {
-                CalleeSaves {
-                    r15: self.regs.get(R15),
-                    r14: self.regs.get(R14),
-                    r13: self.regs.get(R13),
-                    r12: self.regs.get(R12),
-                    rsi: self.regs.get(RSI),
-                    rdi: self.regs.get(RDI),
-                    rbx: self.regs.get(RBX),
-                    rbp: self.regs.get(RBP),
-                }
-            }
-        }
-    }
-
-    fn step(&mut self) -> Result<bool, Self::Error> {
-        self.next().map(|x| x.is_some())
-    }
-
-    fn ip(&mut self) -> mmtk::util::Address {
-        Address::from_ptr(self.regs().ip() as *const u8)
-    }
-
-    fn set_ip(&mut self, ip: mmtk::util::Address) {
-        self.regs.set_ip(ip.as_usize() as _);
-    }
-
-    fn set_sp(&mut self, sp: Address) {
-        self.regs.set_sp(sp.as_usize() as _);
-    }
-
-    fn sp(&mut self) -> Address {
-        Address::from_ptr(self.regs().sp() as *const u8)
-    }
-}
@@ -142,20 +142,17 @@ cfg_if::cfg_if! {
                 0
             }
         }
-    } else if #[cfg(target_os="macos")] {
-        mod impl {
-            static OBJECTS: LazyLock<Vec<Object>> = LazyLock::new(find_objects);
+    } else {
+        mod impl_ {
+            use std::{mem::ManuallyDrop, sync::LazyLock};
 
-            pub fn get_objects() -> &'static [Object] {
-                &OBJECTS
-            }
+            use super::Object;
 
-            fn find_objects() -> Vec<Object> {
-                vec![]
+            /// Loaded objects are only discovered on Linux and FreeBSD, nothing is ever mapped here.
+            pub struct ObjectMmap {
+                pub obj_file: ManuallyDrop<object::File<'static, &'static [u8]>>,
             }
-        }
-    } else {
-        mod impl_ {
+
             static OBJECTS: LazyLock<Vec<Object>> = LazyLock::new(find_objects);
 
             pub fn get_objects() -> &'static [Object] {
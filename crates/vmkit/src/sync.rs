@@ -297,9 +297,7 @@ impl SpinLock {
     }
 }
 
-/*
 pub mod basic_lock;
 pub mod lock_stack;
 pub mod object_monitor;
 pub mod synchronizer;
-*/
@@ -1,12 +1,20 @@
+//! # Lock stack
+//!
+//! Per-thread stack of fast-locked objects. Fast locking only flips lock bits in the lock word, owner of
+//! the lock is the thread which has the object on its lock stack. Recursive locking pushes the same object again.
+
 use std::marker::PhantomData;
 
-use mmtk::util::ObjectReference;
+use mmtk::{util::ObjectReference, vm::RootsWorkFactory};
+
+use crate::{mm::slot::SlotExt, runtime::threads::*, Runtime, SlotOf, ThreadOf};
 
-use crate::{runtime::threads::*, Runtime, ThreadOf};
+/// Maximum number of fast-locked objects per thread, locking more objects inflates their monitors.
+pub const LOCK_STACK_CAPACITY: usize = 8;
 
 pub struct LockStack<R: Runtime> {
     top: u32,
-    base: [Option<ObjectReference>; 8],
+    base: [Option<ObjectReference>; LOCK_STACK_CAPACITY],
     marker: PhantomData<&'static R>,
 }
 
@@ -14,7 +22,7 @@ impl<R: Runtime> LockStack<R> {
     pub const fn new() -> Self {
         Self {
             top: 0,
-            base: [None; 8],
+            base: [None; LOCK_STACK_CAPACITY],
             marker: PhantomData,
         }
     }
@@ -24,7 +32,7 @@ impl<R: Runtime> LockStack<R> {
 
         if ThreadOf::<R>::is_mutator(current) {
             let tls = ThreadOf::<R>::tls(current);
-            return std::ptr::eq(&tls.lock_stack, self);
+            return std::ptr::eq(tls.lock_stack.get(), self);
         }
 
         false
@@ -54,7 +62,7 @@ impl<R: Runtime> LockStack<R> {
         // interested in the balanced locking case when the top oop on the
         // lock-stack matches o. This will cause the for loop to break out
         // in the first loop iteration if it is non-recursive.
-        for i in (0..end).rev() {
+        for i in (1..end).rev() {
             if self.base[i - 1] == Some(o) && self.base[i] == Some(o) {
                 return true;
             }
@@ -72,7 +80,7 @@ impl<R: Runtime> LockStack<R> {
     #[inline]
     pub fn try_recursive_enter(&mut self, o: ObjectReference) -> bool {
         let end = self.top as usize;
-        if end == 0 || self.base[end - 1] != Some(o) {
+        if end == 0 || self.is_full() || self.base[end - 1] != Some(o) {
             // topmost obj does not match o
             return false;
         }
@@ -90,6 +98,7 @@ impl<R: Runtime> LockStack<R> {
         }
 
         self.top -= 1;
+        self.base[end - 1] = None;
         true
     }
 
@@ -110,14 +119,36 @@ impl<R: Runtime> LockStack<R> {
         let removed = end - inserted;
         self.top -= removed as u32;
 
+        for slot in self.base[inserted..end].iter_mut() {
+            *slot = None;
+        }
+
         removed
     }
 
     pub fn contains(&self, obj: ObjectReference) -> bool {
-        self.base.iter().any(|x| x == &Some(obj))
+        self.base[..self.top as usize]
+            .iter()
+            .any(|x| x == &Some(obj))
     }
 
     pub fn is_full(&self) -> bool {
-        self.top == 8
+        self.top as usize == LOCK_STACK_CAPACITY
+    }
+
+    /// Report locked objects to `factory`. Owner thread must be blocked.
+    pub fn scan_roots(&mut self, factory: &mut impl RootsWorkFactory<SlotOf<R>>) {
+        let slots = self.base[..self.top as usize]
+            .iter_mut()
+            .map(|object| {
+                SlotOf::<R>::from_pointer(
+                    object as *mut Option<ObjectReference> as *mut ObjectReference,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        if !slots.is_empty() {
+            factory.create_process_roots_work(slots);
+        }
     }
 }
@@ -1,50 +1,323 @@
-use mmtk::util::{Address, ObjectReference, VMThread};
+//! # Object monitors
+//!
+//! Heavyweight ("inflated") lock of an object. Monitor is created when fast lock can't be used: on contention, on
+//! [`wait`](ObjectMonitor::wait) or when lock stack overflows. Monitors live in [`MonitorTable`] and are referred to by
+//! their index which is stored in the lock word of the object, see [`synchronizer`](super::synchronizer).
+
+use mmtk::util::{Address, ObjectReference};
+use parking_lot::{Condvar, Mutex, MutexGuard};
 use std::{
+    collections::VecDeque,
     ptr::null_mut,
-    sync::atomic::{AtomicBool, AtomicIsize, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+    time::Instant,
 };
 
-pub const TS_UNDEF: u8 = 0;
-pub const TS_READY: u8 = 1;
-pub const TS_RUN: u8 = 2;
-pub const TS_WAIT: u8 = 3;
-pub const TS_ENTER: u8 = 4;
-pub const TS_CXQ: u8 = 5;
+use crate::{runtime::threads::Thread, Runtime, ThreadOf};
+
+/// Monitor is not owned by any thread.
+pub const NO_OWNER: u64 = u64::MAX;
+/// Monitor was inflated by a thread that does not own the fast lock. Real owner still has the object on its lock stack
+/// and claims the monitor on its next operation with it.
+pub const ANONYMOUS_OWNER: u64 = u64::MAX - 1;
 
+/// A thread waiting in [`ObjectMonitor::wait`]. Lives on the stack of the waiting thread.
 pub struct ObjectWaiter {
-    next: AtomicPtr<Self>,
-    prev: AtomicPtr<Self>,
-    thread: VMThread,
-    notified_tid: AtomicU64,
     notified: AtomicBool,
-    tstate: AtomicU8,
-    active: AtomicBool,
 }
 
 impl ObjectWaiter {
-    fn new(current: VMThread) -> Self {
+    fn new() -> Self {
         Self {
-            next: AtomicPtr::new(null_mut()),
-            prev: AtomicPtr::new(null_mut()),
-            active: AtomicBool::new(false),
             notified: AtomicBool::new(false),
-            notified_tid: AtomicU64::new(u64::MAX),
-            thread: current,
-            tstate: AtomicU8::new(TS_RUN),
         }
     }
 }
 
 pub struct ObjectMonitor {
-    /// Backward object pointer
-    object: Option<ObjectReference>,
-    owner: AtomicUsize,
-    /// thread id of the previous owner of the monitor
-    /// Separate owner and next_om on different cache lines since
-    /// both can have busy multi-threaded access. previous_owner_tid is only
-    /// changed by ObjectMonitor::exit() so it is a good choice to share the
-    /// cache line with owner.
-    previous_owner_tid: AtomicU64,
-    next_om: AtomicUsize,
-    recursions: AtomicIsize,
+    /// Backward object pointer, zero if monitor is free. Updated by GC when object moves.
+    object: AtomicUsize,
+    owner: AtomicU64,
+    /// Number of times owner re-entered the monitor. Only accessed by the owner.
+    recursions: AtomicUsize,
+    /// Number of threads blocked trying to enter the monitor, including threads in `wait`. Monitor with contentions
+    /// can't be deflated.
+    contentions: AtomicUsize,
+    /// Threads in `wait`, oldest first. The mutex also guards all blocking transitions of the monitor.
+    waiters: Mutex<VecDeque<*const ObjectWaiter>>,
+    entry_cv: Condvar,
+    wait_cv: Condvar,
+}
+
+unsafe impl Send for ObjectMonitor {}
+unsafe impl Sync for ObjectMonitor {}
+
+impl ObjectMonitor {
+    fn new() -> Self {
+        Self {
+            object: AtomicUsize::new(0),
+            owner: AtomicU64::new(NO_OWNER),
+            recursions: AtomicUsize::new(0),
+            contentions: AtomicUsize::new(0),
+            waiters: Mutex::new(VecDeque::new()),
+            entry_cv: Condvar::new(),
+            wait_cv: Condvar::new(),
+        }
+    }
+
+    pub fn object(&self) -> Option<ObjectReference> {
+        ObjectReference::from_raw_address(unsafe {
+            Address::from_usize(self.object.load(Ordering::Acquire))
+        })
+    }
+
+    pub(crate) fn set_object(&self, object: Option<ObjectReference>) {
+        self.object.store(
+            object.map_or(0, |object| object.to_raw_address().as_usize()),
+            Ordering::Release,
+        );
+    }
+
+    pub fn owner(&self) -> u64 {
+        self.owner.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set_owner(&self, owner: u64) {
+        self.owner.store(owner, Ordering::Release);
+    }
+
+    pub fn recursions(&self) -> usize {
+        self.recursions.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_recursions(&self, recursions: usize) {
+        self.recursions.store(recursions, Ordering::Relaxed);
+    }
+
+    fn try_own(&self, id: u64) -> bool {
+        self.owner
+            .compare_exchange(NO_OWNER, id, Ordering::SeqCst, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Try to enter the monitor without blocking.
+    pub fn try_enter(&self, id: u64) -> bool {
+        if self.owner() == id {
+            self.recursions.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+
+        self.try_own(id)
+    }
+
+    /// Enter the monitor, blocking current thread in parked state until it's available.
+    pub fn enter<R: Runtime>(&self, id: u64) {
+        if self.try_enter(id) {
+            return;
+        }
+
+        // counted before parking: GC can happen once the thread is parked, and the monitor must
+        // not be deflated and reused until the thread acquires it.
+        self.contentions.fetch_add(1, Ordering::SeqCst);
+        ThreadOf::<R>::save_thread_state();
+        ThreadOf::<R>::enter_parked();
+        let mut waiters = self.waiters.lock();
+        self.acquire_contended(id, &mut waiters);
+        // must not hold the monitor lock while blocked for GC: deflation locks it.
+        drop(waiters);
+        ThreadOf::<R>::leave_parked();
+    }
+
+    /// Block until the monitor is owned by `id`. Caller must have incremented `contentions`.
+    fn acquire_contended(
+        &self,
+        id: u64,
+        waiters: &mut MutexGuard<'_, VecDeque<*const ObjectWaiter>>,
+    ) {
+        while !self.try_own(id) {
+            self.entry_cv.wait(waiters);
+        }
+        self.contentions.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Exit the monitor. Must be invoked by the owner.
+    pub fn exit(&self, id: u64) {
+        debug_assert_eq!(self.owner(), id);
+        if self.recursions() != 0 {
+            self.recursions.fetch_sub(1, Ordering::Relaxed);
+            return;
+        }
+
+        self.release();
+    }
+
+    fn release(&self) {
+        self.owner.store(NO_OWNER, Ordering::SeqCst);
+        // contended threads increment `contentions` before checking the owner, either they see
+        // the release or we see them.
+        if self.contentions.load(Ordering::SeqCst) != 0 {
+            let _waiters = self.waiters.lock();
+            self.entry_cv.notify_one();
+        }
+    }
+
+    /// Release the monitor completely and wait until notified or `deadline` passes, then re-enter it. Must be invoked by the owner.
+    ///
+    /// Returns false if wait timed out.
+    pub fn wait<R: Runtime>(&self, id: u64, deadline: Option<Instant>) -> bool {
+        debug_assert_eq!(self.owner(), id);
+        let waiter = ObjectWaiter::new();
+        let recursions = self.recursions.swap(0, Ordering::Relaxed);
+
+        // waiter stays contending until it re-enters, otherwise the monitor could be deflated
+        // between notify and re-entry.
+        self.contentions.fetch_add(1, Ordering::SeqCst);
+        ThreadOf::<R>::save_thread_state();
+        ThreadOf::<R>::enter_parked();
+        let mut waiters = self.waiters.lock();
+        waiters.push_back(&waiter);
+        self.owner.store(NO_OWNER, Ordering::SeqCst);
+        // wake up a thread contending besides this one
+        if self.contentions.load(Ordering::SeqCst) > 1 {
+            self.entry_cv.notify_one();
+        }
+
+        while !waiter.notified.load(Ordering::Relaxed) {
+            match deadline {
+                Some(deadline) => {
+                    if self.wait_cv.wait_until(&mut waiters, deadline).timed_out() {
+                        break;
+                    }
+                }
+                None => self.wait_cv.wait(&mut waiters),
+            }
+        }
+
+        let notified = waiter.notified.load(Ordering::Relaxed);
+        if !notified {
+            waiters.retain(|&w| !std::ptr::eq(w, &waiter));
+        }
+
+        self.acquire_contended(id, &mut waiters);
+        drop(waiters);
+        ThreadOf::<R>::leave_parked();
+
+        self.recursions.store(recursions, Ordering::Relaxed);
+        notified
+    }
+
+    /// Wake up the oldest waiting thread. Must be invoked by the owner.
+    pub fn notify(&self) {
+        let mut waiters = self.waiters.lock();
+        if let Some(waiter) = waiters.pop_front() {
+            unsafe {
+                (*waiter).notified.store(true, Ordering::Relaxed);
+            }
+            self.wait_cv.notify_all();
+        }
+    }
+
+    /// Wake up all waiting threads. Must be invoked by the owner.
+    pub fn notify_all(&self) {
+        let mut waiters = self.waiters.lock();
+        if waiters.is_empty() {
+            return;
+        }
+
+        for waiter in waiters.drain(..) {
+            unsafe {
+                (*waiter).notified.store(true, Ordering::Relaxed);
+            }
+        }
+        self.wait_cv.notify_all();
+    }
+
+    /// Is monitor unowned and has no waiting or contending threads? Only meaningful while mutators are stopped:
+    /// threads blocked on the monitor are parked but change its state under the monitor lock.
+    pub(crate) fn is_idle(&self) -> bool {
+        let waiters = self.waiters.lock();
+        waiters.is_empty()
+            && self.contentions.load(Ordering::SeqCst) == 0
+            && self.owner() == NO_OWNER
+    }
+}
+
+/// Number of monitors allocated at once.
+pub const MONITOR_CHUNK_SIZE: usize = 1024;
+/// Maximum number of monitor chunks, `MONITOR_CHUNK_SIZE * MAX_MONITOR_CHUNKS` monitors can be in use at once.
+pub const MAX_MONITOR_CHUNKS: usize = 4096;
+
+/// Storage of object monitors. Monitors are never freed, free monitors are reused for new inflations so
+/// a monitor reference obtained from the table stays valid forever.
+pub struct MonitorTable {
+    chunks: Box<[AtomicPtr<ObjectMonitor>]>,
+    /// Number of allocated chunks and indices of free monitors in them.
+    free: Mutex<(usize, Vec<u32>)>,
+}
+
+impl MonitorTable {
+    pub fn new() -> Self {
+        Self {
+            chunks: (0..MAX_MONITOR_CHUNKS)
+                .map(|_| AtomicPtr::new(null_mut()))
+                .collect(),
+            free: Mutex::new((0, Vec::new())),
+        }
+    }
+
+    /// Monitor with index `index`. Index must come from [`allocate`](Self::allocate).
+    pub fn get(&self, index: u32) -> &ObjectMonitor {
+        let index = index as usize;
+        let chunk = self.chunks[index / MONITOR_CHUNK_SIZE].load(Ordering::Acquire);
+        debug_assert!(!chunk.is_null(), "invalid monitor index {}", index);
+        unsafe { &*chunk.add(index % MONITOR_CHUNK_SIZE) }
+    }
+
+    /// Take a free monitor and attach it to `object`.
+    pub fn allocate(&self, object: ObjectReference) -> u32 {
+        let mut free = self.free.lock();
+
+        let index = match free.1.pop() {
+            Some(index) => index,
+            None => {
+                let chunk_index = free.0;
+                assert!(chunk_index < MAX_MONITOR_CHUNKS, "out of object monitors");
+                let chunk = (0..MONITOR_CHUNK_SIZE)
+                    .map(|_| ObjectMonitor::new())
+                    .collect::<Box<[_]>>();
+                self.chunks[chunk_index].store(Box::leak(chunk).as_mut_ptr(), Ordering::Release);
+                free.0 += 1;
+
+                let base = (chunk_index * MONITOR_CHUNK_SIZE) as u32;
+                free.1
+                    .extend((1..MONITOR_CHUNK_SIZE as u32).rev().map(|ix| base + ix));
+                base
+            }
+        };
+
+        let monitor = self.get(index);
+        monitor.set_recursions(0);
+        monitor.set_owner(NO_OWNER);
+        monitor.set_object(Some(object));
+        index
+    }
+
+    /// Return idle monitor to the free list.
+    pub fn free(&self, index: u32) {
+        let monitor = self.get(index);
+        monitor.set_object(None);
+        monitor.set_owner(NO_OWNER);
+        self.free.lock().1.push(index);
+    }
+
+    /// Invoke `f` on every monitor attached to an object.
+    pub fn for_each_in_use(&self, mut f: impl FnMut(u32, &ObjectMonitor)) {
+        let chunks = self.free.lock().0;
+        for index in 0..(chunks * MONITOR_CHUNK_SIZE) as u32 {
+            let monitor = self.get(index);
+            if monitor.object.load(Ordering::Acquire) != 0 {
+                f(index, monitor);
+            }
+        }
+    }
 }
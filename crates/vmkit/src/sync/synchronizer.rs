@@ -0,0 +1,403 @@
+//! # Object synchronizer
+//!
+//! `synchronized` semantics on arbitrary heap objects: [`monitor_enter`](ObjectSynchronizer::monitor_enter),
+//! [`monitor_exit`](ObjectSynchronizer::monitor_exit), [`wait`](ObjectSynchronizer::wait),
+//! [`notify`](ObjectSynchronizer::notify) and [`notify_all`](ObjectSynchronizer::notify_all).
+//!
+//! Lock state of an object is kept in its lock word, see [`Runtime::lock_word`], usually the
+//! [mark word](crate::objectmodel::mark_word); runtime must set [`Runtime::LOCK_WORD`] to use the synchronizer.
+//! Low two bits of the word are the lock bits:
+//! - unlocked;
+//! - fast-locked: owner has the object on its [`LockStack`], nothing else is recorded;
+//! - inflated: upper 32 bits hold index of the [`ObjectMonitor`] in the monitor table.
+//!
+//! Uncontended locking only flips lock bits. Monitor is inflated on contention, on `wait` and when lock stack of
+//! the thread is full. Idle monitors are deflated back to unlocked state at the end of every GC. Other bits of the
+//! lock word are never modified.
+//!
+//! All operations must be invoked by mutator threads. Object passed to them must be reachable from roots of
+//! the calling thread, operations that block do so in parked state and GC can move the object meanwhile.
+
+use std::{
+    fmt,
+    marker::PhantomData,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use mmtk::util::{ObjectReference, VMThread};
+
+use crate::{runtime::threads::Thread, Runtime, ThreadOf};
+
+use super::{
+    lock_stack::LockStack,
+    object_monitor::{MonitorTable, ObjectMonitor, ANONYMOUS_OWNER, NO_OWNER},
+};
+
+pub const LOCK_BITS: usize = 0b11;
+pub const UNLOCKED: usize = 0b00;
+pub const FAST_LOCKED: usize = 0b01;
+pub const INFLATED: usize = 0b10;
+/// Position of the monitor index in the lock word of an inflated object.
+pub const MONITOR_SHIFT: usize = 32;
+pub const MONITOR_MASK: usize = (u32::MAX as usize) << MONITOR_SHIFT;
+
+/// Number of times a fast lock held by another thread is re-checked before its monitor is inflated.
+const SPIN_LIMIT: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LockState {
+    Unlocked,
+    FastLocked,
+    /// Index of the monitor in the monitor table.
+    Inflated(u32),
+}
+
+impl LockState {
+    pub fn decode(word: usize) -> Self {
+        match word & LOCK_BITS {
+            FAST_LOCKED => Self::FastLocked,
+            INFLATED => Self::Inflated((word >> MONITOR_SHIFT) as u32),
+            _ => Self::Unlocked,
+        }
+    }
+
+    /// Store this state into `word`, other bits are kept intact.
+    pub fn encode(self, word: usize) -> usize {
+        let word = word & !(LOCK_BITS | MONITOR_MASK);
+        match self {
+            Self::Unlocked => word | UNLOCKED,
+            Self::FastLocked => word | FAST_LOCKED,
+            Self::Inflated(index) => word | INFLATED | ((index as usize) << MONITOR_SHIFT),
+        }
+    }
+}
+
+/// Current thread does not own the lock of an object it tried to unlock, wait or notify on.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct IllegalMonitorState;
+
+impl fmt::Display for IllegalMonitorState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "current thread is not owner of the object lock")
+    }
+}
+
+impl std::error::Error for IllegalMonitorState {}
+
+pub struct ObjectSynchronizer<R: Runtime> {
+    monitors: MonitorTable,
+    marker: PhantomData<R>,
+}
+
+fn lock_word<'a, R: Runtime>(object: ObjectReference) -> &'a AtomicUsize {
+    const {
+        assert!(
+            R::LOCK_WORD,
+            "ObjectSynchronizer requires Runtime::LOCK_WORD"
+        )
+    };
+    unsafe { R::lock_word(object).as_ref() }
+}
+
+fn lock_stack<'a, R: Runtime>(thread: VMThread) -> &'a mut LockStack<R> {
+    unsafe { ThreadOf::<R>::tls(thread).lock_stack_mut_unchecked() }
+}
+
+impl<R: Runtime> ObjectSynchronizer<R> {
+    pub fn new() -> Self {
+        Self {
+            monitors: MonitorTable::new(),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn lock_state(&self, object: ObjectReference) -> LockState {
+        LockState::decode(lock_word::<R>(object).load(Ordering::Acquire))
+    }
+
+    pub fn monitor(&self, index: u32) -> &ObjectMonitor {
+        self.monitors.get(index)
+    }
+
+    /// Lock `object`, blocking until it's available. Locks are recursive.
+    pub fn monitor_enter(&self, object: ObjectReference) {
+        let thread = R::current_thread();
+        let id = ThreadOf::<R>::id(thread);
+        let lock_stack = lock_stack::<R>(thread);
+
+        if lock_stack.try_recursive_enter(object) {
+            return;
+        }
+
+        let word = lock_word::<R>(object);
+        let mut spins = 0;
+
+        loop {
+            let current = word.load(Ordering::Acquire);
+
+            match LockState::decode(current) {
+                LockState::Unlocked if !lock_stack.is_full() => {
+                    if word
+                        .compare_exchange_weak(
+                            current,
+                            LockState::FastLocked.encode(current),
+                            Ordering::Acquire,
+                            Ordering::Relaxed,
+                        )
+                        .is_ok()
+                    {
+                        lock_stack.push(object);
+                        return;
+                    }
+                }
+
+                LockState::FastLocked if !lock_stack.contains(object) && spins < SPIN_LIMIT => {
+                    spins += 1;
+                    std::hint::spin_loop();
+                }
+
+                _ => {
+                    // No safepoint can happen until `enter` counts this thread as contending,
+                    // monitor can't be deflated meanwhile.
+                    let monitor = self.inflate(object, id, lock_stack);
+                    self.claim_anonymous(object, monitor, id, lock_stack);
+                    monitor.enter::<R>(id);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Unlock `object`. Fails if current thread does not own the lock.
+    pub fn monitor_exit(&self, object: ObjectReference) -> Result<(), IllegalMonitorState> {
+        let thread = R::current_thread();
+        let id = ThreadOf::<R>::id(thread);
+        let lock_stack = lock_stack::<R>(thread);
+
+        if lock_stack.try_recursive_exit(object) {
+            return Ok(());
+        }
+
+        let word = lock_word::<R>(object);
+
+        loop {
+            let current = word.load(Ordering::Acquire);
+
+            match LockState::decode(current) {
+                LockState::Unlocked => return Err(IllegalMonitorState),
+
+                LockState::FastLocked => {
+                    if !lock_stack.contains(object) {
+                        return Err(IllegalMonitorState);
+                    }
+
+                    // fails if a contending thread inflated the lock, retry then.
+                    if word
+                        .compare_exchange(
+                            current,
+                            LockState::Unlocked.encode(current),
+                            Ordering::Release,
+                            Ordering::Relaxed,
+                        )
+                        .is_ok()
+                    {
+                        lock_stack.remove(object);
+                        return Ok(());
+                    }
+                }
+
+                LockState::Inflated(index) => {
+                    let monitor = self.monitors.get(index);
+                    self.claim_anonymous(object, monitor, id, lock_stack);
+                    if monitor.owner() != id {
+                        return Err(IllegalMonitorState);
+                    }
+
+                    monitor.exit(id);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Does current thread own the lock of `object`?
+    pub fn holds_lock(&self, object: ObjectReference) -> bool {
+        let thread = R::current_thread();
+
+        match self.lock_state(object) {
+            LockState::Unlocked => false,
+            LockState::FastLocked => lock_stack::<R>(thread).contains(object),
+            LockState::Inflated(index) => {
+                let monitor = self.monitors.get(index);
+                monitor.owner() == ThreadOf::<R>::id(thread)
+                    || (monitor.owner() == ANONYMOUS_OWNER
+                        && lock_stack::<R>(thread).contains(object))
+            }
+        }
+    }
+
+    /// Release lock of `object` and wait until another thread notifies it or `timeout` passes, then re-acquire the lock.
+    /// Lock must be owned by current thread.
+    ///
+    /// Returns false if wait timed out.
+    pub fn wait(
+        &self,
+        object: ObjectReference,
+        timeout: Option<Duration>,
+    ) -> Result<bool, IllegalMonitorState> {
+        let thread = R::current_thread();
+        let id = ThreadOf::<R>::id(thread);
+        let lock_stack = lock_stack::<R>(thread);
+
+        match self.lock_state(object) {
+            LockState::FastLocked if lock_stack.contains(object) => (),
+            LockState::Inflated(_) => (),
+            _ => return Err(IllegalMonitorState),
+        }
+
+        let monitor = self.inflate(object, id, lock_stack);
+        self.claim_anonymous(object, monitor, id, lock_stack);
+        if monitor.owner() != id {
+            return Err(IllegalMonitorState);
+        }
+
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        Ok(monitor.wait::<R>(id, deadline))
+    }
+
+    /// Wake up one thread waiting on `object`. Lock must be owned by current thread.
+    pub fn notify(&self, object: ObjectReference) -> Result<(), IllegalMonitorState> {
+        if let Some(monitor) = self.owned_monitor(object)? {
+            monitor.notify();
+        }
+
+        Ok(())
+    }
+
+    /// Wake up all threads waiting on `object`. Lock must be owned by current thread.
+    pub fn notify_all(&self, object: ObjectReference) -> Result<(), IllegalMonitorState> {
+        if let Some(monitor) = self.owned_monitor(object)? {
+            monitor.notify_all();
+        }
+
+        Ok(())
+    }
+
+    /// Monitor of `object` owned by current thread, `None` if object is fast-locked: nobody can wait on it then.
+    fn owned_monitor(
+        &self,
+        object: ObjectReference,
+    ) -> Result<Option<&ObjectMonitor>, IllegalMonitorState> {
+        let thread = R::current_thread();
+        let id = ThreadOf::<R>::id(thread);
+        let lock_stack = lock_stack::<R>(thread);
+
+        match self.lock_state(object) {
+            LockState::FastLocked if lock_stack.contains(object) => Ok(None),
+            LockState::Inflated(index) => {
+                let monitor = self.monitors.get(index);
+                self.claim_anonymous(object, monitor, id, lock_stack);
+                if monitor.owner() != id {
+                    return Err(IllegalMonitorState);
+                }
+
+                Ok(Some(monitor))
+            }
+            _ => Err(IllegalMonitorState),
+        }
+    }
+
+    /// Inflate the monitor of `object` unless it's already inflated. If current thread holds fast lock of the object
+    /// it becomes owner of the monitor, if another thread does the monitor is owned anonymously until that thread claims it.
+    fn inflate<'a>(
+        &'a self,
+        object: ObjectReference,
+        id: u64,
+        lock_stack: &mut LockStack<R>,
+    ) -> &'a ObjectMonitor {
+        let word = lock_word::<R>(object);
+
+        loop {
+            let current = word.load(Ordering::Acquire);
+
+            let owner = match LockState::decode(current) {
+                LockState::Inflated(index) => return self.monitors.get(index),
+                LockState::Unlocked => NO_OWNER,
+                LockState::FastLocked if lock_stack.contains(object) => id,
+                LockState::FastLocked => ANONYMOUS_OWNER,
+            };
+
+            let index = self.monitors.allocate(object);
+            let monitor = self.monitors.get(index);
+            monitor.set_owner(owner);
+
+            if word
+                .compare_exchange(
+                    current,
+                    LockState::Inflated(index).encode(current),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                if owner == id {
+                    monitor.set_recursions(lock_stack.remove(object) - 1);
+                }
+
+                return monitor;
+            }
+
+            self.monitors.free(index);
+        }
+    }
+
+    /// Take ownership of an anonymously owned monitor if current thread holds fast lock of `object`.
+    fn claim_anonymous(
+        &self,
+        object: ObjectReference,
+        monitor: &ObjectMonitor,
+        id: u64,
+        lock_stack: &mut LockStack<R>,
+    ) {
+        if monitor.owner() == ANONYMOUS_OWNER && lock_stack.contains(object) {
+            monitor.set_recursions(lock_stack.remove(object) - 1);
+            monitor.set_owner(id);
+        }
+    }
+
+    /// Update backward pointers of monitors after transitive closure. Monitors of dead objects are freed: threads
+    /// blocked on a monitor keep its object reachable.
+    pub(crate) fn process_weak_monitors(&self) {
+        self.monitors.for_each_in_use(|index, monitor| {
+            let object = monitor.object().unwrap();
+
+            if object.is_reachable() {
+                monitor.set_object(Some(object.get_forwarded_object().unwrap_or(object)));
+            } else {
+                self.monitors.free(index);
+            }
+        });
+    }
+
+    /// Deflate monitors that have no owner, waiters or contending threads. Invoked at the end of GC
+    /// while mutators are stopped.
+    pub(crate) fn deflate_idle_monitors(&self) {
+        // Invoked for every runtime: monitors can only be inflated through a lock word.
+        if !R::LOCK_WORD {
+            return;
+        }
+
+        self.monitors.for_each_in_use(|index, monitor| {
+            if !monitor.is_idle() {
+                return;
+            }
+
+            let word = unsafe { R::lock_word(monitor.object().unwrap()).as_ref::<AtomicUsize>() };
+            let current = word.load(Ordering::Relaxed);
+            debug_assert_eq!(LockState::decode(current), LockState::Inflated(index));
+            word.store(LockState::Unlocked.encode(current), Ordering::Release);
+            self.monitors.free(index);
+        });
+    }
+}
@@ -0,0 +1,97 @@
+//! Identity hash checks shared by per-plan tests. Every plan is a separate test binary: flags and MMTk
+//! instance are process-wide and can be initialized only once.
+
+use std::num::NonZeroUsize;
+
+use vmkit::{
+    mm::{vmkit_allocate, vmkit_object_hash, vmkit_request_gc},
+    mmtk::util::{ObjectReference, VMMutatorThread},
+    mock::{MockThread, MockVM},
+    objectmodel::vtable::{FinalizeCallback, GCVTable, TraceCallback},
+    runtime::{options::MMTKFlags, threads::Thread},
+    ThreadOf,
+};
+
+/// Object size, header included.
+const OBJECT_SIZE: usize = 32;
+
+static VTABLE: GCVTable<MockVM> = GCVTable {
+    magic: GCVTable::<MockVM>::MAGIC,
+    size: OBJECT_SIZE,
+    alignment: NonZeroUsize::new(8).unwrap(),
+    compute_size: None,
+    trace: TraceCallback::NoTrace,
+    finalize: FinalizeCallback::None,
+};
+
+fn allocate(thread: VMMutatorThread) -> ObjectReference {
+    vmkit_allocate::<MockVM>(thread, OBJECT_SIZE, (&VTABLE).into())
+}
+
+/// Number of objects kept alive across collections.
+const LIVE_OBJECTS: usize = 128;
+
+/// Dead objects around live ones leave holes in blocks, so Immix picks them for defragmentation.
+fn allocate_garbage(thread: VMMutatorThread) {
+    for _ in 0..64 {
+        allocate(thread);
+    }
+}
+
+/// Hash live objects, collect twice and check that hashes stay the same. Plans that always copy pass
+/// `always_moves` and every collection must move every object. Other plans must move at least one object
+/// over both collections, so the test fails if nothing moved and the moved path was not checked.
+pub fn hash_survives_moves(flags: &[&str], always_moves: bool) {
+    vmkit::utils::flags::parse_with_prefix::<MMTKFlags>(
+        "gc",
+        flags.iter().map(|flag| flag.to_string()),
+        std::iter::empty(),
+    )
+    .unwrap();
+
+    let (handle, _thread) = MockThread::spawn(move |thread| {
+        let thread = VMMutatorThread(thread);
+        let stack = ThreadOf::<MockVM>::tls(thread.0).shadow_stack();
+
+        // capacity is reserved up front: elements never move while they are rooted
+        let mut objects = Vec::with_capacity(LIVE_OBJECTS);
+        let frame = stack.enter_roots_frame(LIVE_OBJECTS);
+        for ix in 0..LIVE_OBJECTS {
+            allocate_garbage(thread);
+            objects.push(allocate(thread));
+            unsafe { frame.save_root_unchecked(ix, objects.last_mut().unwrap()) };
+        }
+
+        let hashes = objects
+            .iter()
+            .map(|object| vmkit_object_hash::<MockVM>(*object))
+            .collect::<Vec<_>>();
+
+        // first move stores the hash word, second one copies it
+        let mut total_moved = 0;
+        for _ in 0..2 {
+            let before = objects.clone();
+            allocate_garbage(thread);
+            vmkit_request_gc::<MockVM>();
+
+            let moved = objects
+                .iter()
+                .zip(&before)
+                .filter(|(object, before)| object != before)
+                .count();
+            if always_moves {
+                assert_eq!(moved, LIVE_OBJECTS, "not every object was moved");
+            }
+            total_moved += moved;
+
+            for (object, hash) in objects.iter().zip(&hashes) {
+                assert_eq!(vmkit_object_hash::<MockVM>(*object), *hash);
+            }
+        }
+
+        drop(frame);
+        assert!(total_moved > 0, "no object was moved");
+    });
+
+    handle.unwrap().join().unwrap();
+}
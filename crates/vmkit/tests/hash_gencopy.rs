@@ -0,0 +1,7 @@
+mod common;
+
+/// First GC copies the object out of the nursery, full-heap system GC copies it again within mature space.
+#[test]
+fn hash_survives_moves() {
+    common::hash_survives_moves(&["--gc:plan=gencopy", "--gc:full-heap-system-gc"], true);
+}
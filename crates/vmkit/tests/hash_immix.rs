@@ -0,0 +1,8 @@
+mod common;
+
+/// Immix only evacuates blocks it picks for defragmentation, so only some of the objects move.
+/// Full-heap system GC makes Immix defragment.
+#[test]
+fn hash_survives_defrag() {
+    common::hash_survives_moves(&["--gc:plan=immix", "--gc:full-heap-system-gc"], false);
+}
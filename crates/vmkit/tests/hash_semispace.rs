@@ -0,0 +1,6 @@
+mod common;
+
+#[test]
+fn hash_survives_moves() {
+    common::hash_survives_moves(&["--gc:plan=semispace"], true);
+}